@@ -1,4 +1,4 @@
 #!/usr/bin/env bash
 # Build and deploy
 spin build
-spin deploy
\ No newline at end of file
+spin deploy --variable redis_address="$(cat redis.env 2>/dev/null)"
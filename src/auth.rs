@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+
+use http::HeaderValue;
+use serde_json::Value;
+use spin_sdk::http::{Request, Response};
+
+use crate::error::{ApiError, ApiResult};
+use crate::req_helpers::parse_query;
+use crate::router::Context;
+
+/// Query parameters that used to carry secrets
+const SECRET_PARAMS: [&str; 2] = ["host_secret", "client_secret"];
+
+/// The token from an `Authorization: Bearer <token>` header, if there is one
+pub fn bearer_token(req: &Request) -> Option<&str> {
+    let value = req.headers().get("Authorization")?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+
+    Some(token.trim())
+        .filter(|token| scheme.eq_ignore_ascii_case("bearer") && !token.is_empty())
+}
+
+/// The caller's secret, from the Authorization header or else the (deprecated) query parameter
+pub fn query_secret<'a>(req: &'a Request, query: &'a HashMap<String, String>, key: &str) -> ApiResult<&'a str> {
+    bearer_token(req)
+        .or_else(|| query.get(key).map(String::as_str))
+        .ok_or(ApiError::Unauthenticated)
+}
+
+/// The caller's secret, from the Authorization header or else the JSON body
+pub fn json_secret<'a>(req: &'a Request, body: &'a Value, key: &str) -> ApiResult<&'a str> {
+    bearer_token(req)
+        .or_else(|| body[key].as_str())
+        .ok_or(ApiError::Unauthenticated)
+}
+
+/// Secrets in the query string end up in access logs, proxies and browser history. They're
+/// still accepted while allow_query_secrets is on, but the response says they're deprecated
+pub fn query_secrets(ctx: &Context, next: &dyn Fn() -> ApiResult<Response>) -> ApiResult<Response> {
+    let query = parse_query(ctx.req)?;
+    if !SECRET_PARAMS.iter().any(|key| query.contains_key(*key)) {
+        return next();
+    }
+
+    if !ctx.config.allow_query_secrets {
+        return Err(ApiError::SecretInQuery);
+    }
+
+    let mut res = next()?;
+    res.headers_mut().insert("Deprecation", HeaderValue::from_static("true"));
+    Ok(res)
+}
@@ -0,0 +1,58 @@
+use http::{HeaderValue, Method};
+use spin_sdk::http::Response;
+
+use crate::error::{ApiError, ApiResult};
+use crate::router::Context;
+
+/// Applies the configured CORS policy to every response, errors included, and fills in
+/// preflight responses. The router already answers OPTIONS with the methods a path allows,
+/// this just tells the browser which of them it may use cross-origin
+pub fn cors(ctx: &Context, next: &dyn Fn() -> ApiResult<Response>) -> ApiResult<Response> {
+    let mut res = next().or_else(|e| e.into_response().map_err(ApiError::Internal))?;
+
+    let config = ctx.config;
+    let headers = res.headers_mut();
+
+    // Unless anyone is allowed, the answer depends on who's asking
+    if !config.allows_any_origin() {
+        headers.append("Vary", HeaderValue::from_static("Origin"));
+    }
+
+    let Some(origin) = ctx.req.headers().get("Origin").and_then(|origin| origin.to_str().ok()) else {
+        return Ok(res);
+    };
+
+    if !config.allows_origin(origin) {
+        // No CORS headers at all, the browser takes it from there
+        return Ok(res);
+    }
+
+    let allow_origin = if config.allows_any_origin() {
+        HeaderValue::from_static("*")
+    } else {
+        HeaderValue::from_str(origin).map_err(|e| ApiError::Internal(e.into()))?
+    };
+    headers.insert("Access-Control-Allow-Origin", allow_origin);
+
+    if config.cors_allow_credentials {
+        headers.insert("Access-Control-Allow-Credentials", HeaderValue::from_static("true"));
+    }
+
+    let is_preflight = ctx.req.method() == Method::OPTIONS
+        && ctx.req.headers().contains_key("Access-Control-Request-Method");
+
+    if is_preflight {
+        if let Some(allow) = headers.get("Allow").cloned() {
+            headers.insert("Access-Control-Allow-Methods", allow);
+        }
+
+        let allow_headers = config.cors_allow_headers.join(", ");
+        headers.insert(
+            "Access-Control-Allow-Headers",
+            HeaderValue::from_str(&allow_headers).map_err(|e| ApiError::Internal(e.into()))?,
+        );
+        headers.insert("Access-Control-Max-Age", HeaderValue::from(config.cors_max_age_seconds));
+    }
+
+    Ok(res)
+}
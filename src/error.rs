@@ -0,0 +1,199 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use serde_json::json;
+use spin_sdk::http::Response;
+
+/// Everything a request can fail with, and how each one looks to the caller
+#[derive(Debug)]
+pub enum ApiError {
+    /// A required parameter wasn't given, with its name
+    MissingParameter(String),
+    /// A parameter was given but doesn't make sense
+    InvalidParameter(String),
+    /// The body isn't JSON
+    InvalidBody,
+    /// A signaling message doesn't fit the schema, or isn't the sender's to send
+    InvalidMessage(String),
+    /// A secret was sent in the query string with allow_query_secrets off
+    SecretInQuery,
+    /// Wrong or expired secret
+    Unauthenticated,
+    /// The client, or where they're coming from, is banned from the session
+    Banned,
+    WrongPassword,
+    /// The client is still waiting on the host, who has to let them in before answering them
+    JoinNotAccepted,
+    /// No route here
+    NotFound,
+    /// There's a route here, but not for this method. Which ones there are, for the Allow header
+    MethodNotAllowed { allow: String },
+    NoSuchSession,
+    NoSuchClient,
+    /// The host tried to decide on a client that isn't waiting on a decision
+    NoPendingJoin,
+    /// Someone in the session already goes by this name
+    NameTaken,
+    SessionFull,
+    /// An SDP, candidate or message is over the configured limit, with which
+    PayloadTooLarge(String),
+    /// The recipient's mailbox is full and mailbox_overflow is reject, with how many seconds to
+    /// give them to catch up
+    MailboxFull { retry_after: i64 },
+    /// Too many attempts, and how many seconds until the next one is allowed
+    TooManyRequests { retry_after: i64 },
+    /// The backing store failed us
+    StoreUnavailable(anyhow::Error),
+    /// Anything else that's our fault
+    Internal(anyhow::Error),
+}
+
+pub type ApiResult<T> = std::result::Result<T, ApiError>;
+
+impl ApiError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ApiError::MissingParameter(_)
+            | ApiError::InvalidParameter(_)
+            | ApiError::InvalidBody
+            | ApiError::InvalidMessage(_)
+            | ApiError::SecretInQuery => 400,
+            ApiError::Unauthenticated => 401,
+            ApiError::Banned | ApiError::WrongPassword | ApiError::JoinNotAccepted => 403,
+            ApiError::NotFound | ApiError::NoSuchSession | ApiError::NoSuchClient | ApiError::NoPendingJoin => 404,
+            ApiError::MethodNotAllowed { .. } => 405,
+            ApiError::NameTaken | ApiError::SessionFull => 409,
+            ApiError::PayloadTooLarge(_) => 413,
+            ApiError::TooManyRequests { .. } | ApiError::MailboxFull { .. } => 429,
+            ApiError::Internal(_) => 500,
+            ApiError::StoreUnavailable(_) => 503,
+        }
+    }
+
+    /// Machine readable and stable, unlike the message
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::MissingParameter(_) => "missing_parameter",
+            ApiError::InvalidParameter(_) => "invalid_parameter",
+            ApiError::InvalidBody => "invalid_body",
+            ApiError::InvalidMessage(_) => "invalid_message",
+            ApiError::SecretInQuery => "secret_in_query",
+            ApiError::Unauthenticated => "unauthenticated",
+            ApiError::Banned => "banned",
+            ApiError::WrongPassword => "wrong_password",
+            ApiError::JoinNotAccepted => "join_not_accepted",
+            ApiError::NotFound => "not_found",
+            ApiError::MethodNotAllowed { .. } => "method_not_allowed",
+            ApiError::NoSuchSession => "no_such_session",
+            ApiError::NoSuchClient => "no_such_client",
+            ApiError::NoPendingJoin => "no_pending_join",
+            ApiError::NameTaken => "name_taken",
+            ApiError::SessionFull => "session_full",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::MailboxFull { .. } => "mailbox_full",
+            ApiError::TooManyRequests { .. } => "too_many_requests",
+            ApiError::StoreUnavailable(_) => "store_unavailable",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// The error as the caller sees it. Server side failures are logged here, and only a
+    /// generic message goes out
+    pub fn into_response(self) -> Result<Response> {
+        if let ApiError::StoreUnavailable(e) | ApiError::Internal(e) = &self {
+            eprintln!("{}: {e:?}", self.code());
+        }
+
+        let res_body = json!({
+            "success": false,
+            "error": {
+                "code": self.code(),
+                "message": self.to_string(),
+            },
+        });
+
+        let mut res = http::Response::builder()
+            .status(self.status())
+            .header("Content-Type", "application/json");
+
+        match self {
+            ApiError::TooManyRequests { retry_after } | ApiError::MailboxFull { retry_after } => {
+                res = res.header("Retry-After", retry_after);
+            },
+            ApiError::MethodNotAllowed { allow } => res = res.header("Allow", allow),
+            ApiError::Unauthenticated => res = res.header("WWW-Authenticate", "Bearer"),
+            _ => {},
+        }
+
+        res.body(Some(res_body.to_string().into()))
+            .map_err(|_| anyhow!("Failed to build response"))
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::MissingParameter(key) => write!(f, "missing required parameter {key}"),
+            ApiError::InvalidParameter(message)
+            | ApiError::InvalidMessage(message)
+            | ApiError::PayloadTooLarge(message) => write!(f, "{message}"),
+            ApiError::InvalidBody => write!(f, "Invalid body"),
+            ApiError::SecretInQuery => write!(f, "Secrets go in an Authorization: Bearer header, not the query string"),
+            ApiError::Unauthenticated => write!(f, "Not authenticated"),
+            ApiError::Banned => write!(f, "Banned from this session"),
+            ApiError::WrongPassword => write!(f, "Wrong password"),
+            ApiError::JoinNotAccepted => write!(f, "Join not accepted"),
+            ApiError::NotFound => write!(f, "Not found"),
+            ApiError::MethodNotAllowed { .. } => write!(f, "Method not allowed"),
+            ApiError::NoSuchSession => write!(f, "No such session"),
+            ApiError::NoSuchClient => write!(f, "No such client"),
+            ApiError::NoPendingJoin => write!(f, "No pending join for this client"),
+            ApiError::NameTaken => write!(f, "Name already taken"),
+            ApiError::SessionFull => write!(f, "Session is full"),
+            ApiError::MailboxFull { .. } => write!(f, "Recipient's mailbox is full, try again later"),
+            ApiError::TooManyRequests { .. } => write!(f, "Too many attempts, try again later"),
+            ApiError::StoreUnavailable(_) => write!(f, "Storage is unavailable, try again later"),
+            ApiError::Internal(_) => write!(f, "Something went wrong on our end"),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// A call into the backend itself failed, e.g. Redis can't be reached. Store helpers raise it
+/// so it can be told apart from them getting back something they can't make sense of
+#[derive(Debug)]
+pub struct StoreFailure(String);
+
+impl StoreFailure {
+    pub fn error(message: impl Into<String>) -> anyhow::Error {
+        StoreFailure(message.into()).into()
+    }
+}
+
+impl fmt::Display for StoreFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for StoreFailure {}
+
+/// Store methods return anyhow errors. The ones that mean something to the caller are an
+/// ApiError underneath, a StoreFailure anywhere in the chain is the backend falling over, and
+/// anything else is a bug on our end
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<ApiError>() {
+            Ok(e) => e,
+            Err(e) if e.chain().any(|cause| cause.is::<StoreFailure>()) => ApiError::StoreUnavailable(e),
+            Err(e) => ApiError::Internal(e),
+        }
+    }
+}
+
+impl From<http::Error> for ApiError {
+    fn from(e: http::Error) -> Self {
+        ApiError::Internal(e.into())
+    }
+}
@@ -0,0 +1,575 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use rand::Rng;
+use serde_json::{json, Map, Value};
+use spin_sdk::key_value::{Error, Store};
+
+use crate::config::Config;
+use crate::error::StoreFailure;
+use crate::random_util::generate_secret;
+use crate::store::{
+    self, ClientStatus, Mailbox, MailboxMessage, PublicSession, SessionOptions, SignalingStore,
+};
+
+/// Object of public session name -> expiry time
+const PUBLIC_INDEX_KEY: &str = "sessions:public";
+
+/// How often a read checks back for messages while waiting
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Registering a session sweeps out expired keys with a 1 in this chance
+const SWEEP_ONE_IN: u32 = 20;
+
+/// Signaling store backed by Spin's built-in key-value store.
+///
+/// The key-value store has no expiration or atomic list operations, so every value is wrapped
+/// in a record with its own expiry and checked on read, and mailboxes are read-modify-write.
+/// Concurrent writers to the same mailbox can therefore lose messages, which is fine for the
+/// small deployments this is meant for. The same goes for joins, which check for a free name and
+/// place before registering the client (see SignalingStore::claim_client), so two joins racing
+/// for the same name or the last place can both get in.
+pub struct KvHelper {
+    store: Store,
+    config: Config,
+}
+
+impl KvHelper {
+    pub fn open(config: &Config) -> Result<Self> {
+        let store = Store::open_default().map_err(|e| StoreFailure::error(format!("Failed to open key-value store: {e:?}")))?;
+        Ok(Self { store, config: config.clone() })
+    }
+
+    /// Reads the value stored under key, treating anything past its expiry as missing
+    fn get_record(&self, key: &str) -> Result<Option<Value>> {
+        let bytes = match self.store.get(key) {
+            Ok(bytes) => bytes,
+            Err(Error::NoSuchKey) => return Ok(None),
+            Err(e) => return Err(StoreFailure::error(format!("Failed to read {key}: {e:?}"))),
+        };
+
+        let record: Value = serde_json::from_slice(&bytes).with_context(|| format!("Invalid record {key}"))?;
+
+        match record["expires_at"].as_i64() {
+            Some(expires_at) if expires_at <= store::now() => {
+                self.delete(key)?;
+                Ok(None)
+            },
+            _ => Ok(Some(record["value"].clone())),
+        }
+    }
+
+    /// Stores value under key, to be treated as gone after expires_at (if any)
+    fn set_record(&self, key: &str, value: &Value, expires_at: Option<i64>) -> Result<()> {
+        let record = json!({
+            "expires_at": expires_at,
+            "value": value,
+        });
+
+        self.store.set(key, record.to_string()).map_err(|e| StoreFailure::error(format!("Failed to write {key}: {e:?}")))
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        match self.store.delete(key) {
+            Ok(()) | Err(Error::NoSuchKey) => Ok(()),
+            Err(e) => Err(StoreFailure::error(format!("Failed to delete {key}: {e:?}"))),
+        }
+    }
+
+    /// Nothing expires by itself here, so every so often look through everything and clean up
+    fn sweep_expired(&self) -> Result<()> {
+        let keys = self.store.get_keys().map_err(|e| StoreFailure::error(format!("Failed to list keys: {e:?}")))?;
+
+        for key in keys {
+            // Reading drops it if it's expired
+            self.get_record(&key)?;
+        }
+
+        Ok(())
+    }
+
+    fn get_public_index(&self) -> Result<Map<String, Value>> {
+        match self.get_record(PUBLIC_INDEX_KEY)? {
+            Some(Value::Object(index)) => Ok(index),
+            _ => Ok(Map::new()),
+        }
+    }
+
+    /// Reads a mailbox record, { next_id, messages: [[id, message], ...] }
+    fn get_mailbox(&self, key: &str) -> Result<Value> {
+        match self.get_record(key)? {
+            Some(record) if record["messages"].is_array() => Ok(record),
+            _ => Ok(json!({ "next_id": 0, "messages": [] })),
+        }
+    }
+
+    fn mailbox_entries(record: &Value) -> impl Iterator<Item = (u64, &Value)> {
+        record["messages"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| Some((entry[0].as_u64()?, &entry[1])))
+    }
+
+    /// Moves the expiry of whatever is stored under key, if anything
+    fn touch(&self, key: &str, expires_at: i64) -> Result<()> {
+        match self.get_record(key)? {
+            Some(value) => self.set_record(key, &value, Some(expires_at)),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads a session's client list, { client name: status }
+    fn get_client_list(&self, session_name: &str) -> Result<Map<String, Value>> {
+        match self.get_record(&store::client_list_key(session_name))? {
+            Some(Value::Object(clients)) => Ok(clients),
+            _ => Ok(Map::new()),
+        }
+    }
+
+    fn set_client_list(&self, session_name: &str, clients: Map<String, Value>) -> Result<()> {
+        let expires_at = store::now() + self.config.session_ttl_seconds;
+        self.set_record(&store::client_list_key(session_name), &Value::Object(clients), Some(expires_at))
+    }
+
+    /// Takes a client off the session's client list, if they're on it
+    fn unlist_client(&self, session_name: &str, client_name: &str) -> Result<()> {
+        let mut clients = self.get_client_list(session_name)?;
+        if clients.remove(client_name).is_some() {
+            self.set_client_list(session_name, clients)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a session's waiting list, [[client name, offer], ...]
+    fn get_waiting_list(&self, session_name: &str) -> Result<Vec<Value>> {
+        match self.get_record(&store::waiting_list_key(session_name))? {
+            Some(Value::Array(waiting)) => Ok(waiting),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn set_waiting_list(&self, session_name: &str, waiting: Vec<Value>) -> Result<()> {
+        let expires_at = store::now() + self.config.session_ttl_seconds;
+        self.set_record(&store::waiting_list_key(session_name), &Value::Array(waiting), Some(expires_at))
+    }
+
+    /// Reads a JSON object record, or an empty one if there isn't one
+    fn get_object(&self, key: &str) -> Result<Map<String, Value>> {
+        match self.get_record(key)? {
+            Some(Value::Object(object)) => Ok(object),
+            _ => Ok(Map::new()),
+        }
+    }
+
+    fn get_session_property(&self, session_name: &str, field: &str) -> Result<Option<Value>> {
+        let session = self.get_record(&store::session_key(session_name))?;
+        Ok(session.map(|session| session[field].clone()))
+    }
+}
+
+impl SignalingStore for KvHelper {
+    fn get_test_value(&self) -> Result<u32> {
+        match self.store.get("test") {
+            Ok(value) => Ok(std::str::from_utf8(&value)?.parse::<u32>()?),
+            Err(Error::NoSuchKey) => Ok(0u32),
+            Err(e) => Err(StoreFailure::error(format!("Failed to read value: {e:?}"))),
+        }
+    }
+
+    fn set_test_value(&self, val: u32) -> Result<()> {
+        self.store.set("test", val.to_string()).map_err(|e| StoreFailure::error(format!("Failed to update value: {e:?}")))
+    }
+
+    fn has_session(&self, session_name: &str) -> Result<bool> {
+        Ok(self.get_record(&store::session_key(session_name))?.is_some())
+    }
+
+    fn register_session(
+        &self,
+        session_name: &str,
+        is_public: bool,
+        host_name: &str,
+        options: &SessionOptions,
+    ) -> Result<Option<String>> {
+        if rand::thread_rng().gen_ratio(1, SWEEP_ONE_IN) {
+            self.sweep_expired()?;
+        }
+
+        // No transactions here, so check, write, then read back to see if someone else got in
+        // between. That narrows the race down a lot, but two hosts who both check before either
+        // writes still both get the name, and the later one's session replaces the earlier.
+        // Generated names make that unlikely, not impossible
+        let key = store::session_key(session_name);
+        if self.has_session(session_name)? {
+            return Ok(None);
+        }
+
+        let host_secret = generate_secret(self.config.secret_length);
+        let created_at = store::now();
+        let expires_at = created_at + self.config.session_ttl_seconds;
+
+        self.set_record(&key, &json!({
+            "public": is_public,
+            "host_name": host_name,
+            "host_secret": host_secret,
+            "created_at": created_at,
+            "max_clients": options.max_clients,
+            "waiting_list": options.waiting_list,
+            "password_hash": options.password_hash,
+        }), Some(expires_at))?;
+
+        if self.get_host_secret(session_name)?.as_deref() != Some(host_secret.as_str()) {
+            return Ok(None);
+        }
+
+        if is_public {
+            let mut index = self.get_public_index()?;
+            index.insert(session_name.into(), json!(expires_at));
+            self.set_record(PUBLIC_INDEX_KEY, &Value::Object(index), None)?;
+        }
+
+        Ok(Some(host_secret))
+    }
+
+    fn get_session_options(&self, session_name: &str) -> Result<Option<SessionOptions>> {
+        let session = self.get_record(&store::session_key(session_name))?;
+
+        Ok(session.map(|session| SessionOptions {
+            max_clients: session["max_clients"].as_u64().map(|max_clients| max_clients as usize),
+            waiting_list: session["waiting_list"].as_bool().unwrap_or(false),
+            password_hash: session["password_hash"].as_str().map(String::from),
+        }))
+    }
+
+    fn renew_session(&self, session_name: &str) -> Result<Option<i64>> {
+        let key = store::session_key(session_name);
+        let Some(session) = self.get_record(&key)? else {
+            return Ok(None);
+        };
+
+        let expires_at = store::now() + self.config.session_ttl_seconds;
+        self.set_record(&key, &session, Some(expires_at))?;
+
+        if session["public"].as_bool() == Some(true) {
+            let mut index = self.get_public_index()?;
+            index.insert(session_name.into(), json!(expires_at));
+            self.set_record(PUBLIC_INDEX_KEY, &Value::Object(index), None)?;
+        }
+
+        self.touch(&Mailbox::Host { session_name }.key(), expires_at)?;
+        self.touch(&store::client_list_key(session_name), expires_at)?;
+        self.touch(&store::waiting_list_key(session_name), expires_at)?;
+        self.touch(&store::client_address_key(session_name), expires_at)?;
+        self.touch(&store::ban_list_key(session_name), expires_at)?;
+        for client_name in self.get_client_list(session_name)?.keys() {
+            let client_name = client_name.as_str();
+            self.touch(&store::client_secret_key(session_name, client_name), expires_at)?;
+            self.touch(&Mailbox::Client { session_name, client_name }.key(), expires_at)?;
+            self.touch(&Mailbox::HeldForHost { session_name, client_name }.key(), expires_at)?;
+            self.touch(&Mailbox::HeldForClient { session_name, client_name }.key(), expires_at)?;
+        }
+
+        Ok(Some(expires_at))
+    }
+
+    fn delete_session(&self, session_name: &str) -> Result<()> {
+        let mut index = self.get_public_index()?;
+        if index.remove(session_name).is_some() {
+            self.set_record(PUBLIC_INDEX_KEY, &Value::Object(index), None)?;
+        }
+
+        self.delete(&store::session_key(session_name))?;
+        self.delete(&Mailbox::Host { session_name }.key())?;
+        self.delete(&store::client_list_key(session_name))?;
+        self.delete(&store::waiting_list_key(session_name))?;
+        self.delete(&store::client_address_key(session_name))?;
+        self.delete(&store::ban_list_key(session_name))
+    }
+
+    fn get_host_secret(&self, session_name: &str) -> Result<Option<String>> {
+        let secret = self.get_session_property(session_name, "host_secret")?;
+        Ok(secret.and_then(|secret| secret.as_str().map(String::from)))
+    }
+
+    fn list_public_sessions(
+        &self,
+        prefix: Option<&str>,
+        min_age: Option<i64>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<PublicSession>, Option<String>)> {
+        let now = store::now();
+
+        // Prune anything that has expired, the index is sorted by name already
+        let mut index = self.get_public_index()?;
+        let before = index.len();
+        index.retain(|_, expires_at| expires_at.as_i64().is_some_and(|expires_at| expires_at > now));
+        if index.len() != before {
+            self.set_record(PUBLIC_INDEX_KEY, &Value::Object(index.clone()), None)?;
+        }
+
+        let mut ret = Vec::new();
+
+        let names = index.keys()
+            .filter(|name| name.starts_with(prefix.unwrap_or_default()))
+            .filter(|name| cursor.is_none_or(|cursor| name.as_str() > cursor));
+
+        for name in names {
+            let Some(session) = self.get_record(&store::session_key(name))? else {
+                continue;
+            };
+
+            let created_at = session["created_at"].as_i64().unwrap_or(now);
+            if now - created_at < min_age.unwrap_or(0) {
+                continue;
+            }
+
+            ret.push(PublicSession {
+                session_name: name.clone(),
+                host_name: session["host_name"].as_str().unwrap_or_default().into(),
+                created_at,
+            });
+
+            if ret.len() >= limit {
+                return Ok((ret, Some(name.clone())));
+            }
+        }
+
+        Ok((ret, None))
+    }
+
+    fn register_client_secret(&self, session_name: &str, client_name: &str, status: ClientStatus) -> Result<String> {
+        let secret = generate_secret(self.config.secret_length);
+        let key = store::client_secret_key(session_name, client_name);
+        let expires_at = store::now() + self.config.session_ttl_seconds;
+
+        self.set_record(&key, &json!(secret), Some(expires_at))?;
+
+        let mut clients = self.get_client_list(session_name)?;
+        clients.insert(client_name.into(), json!(status.as_str()));
+        self.set_client_list(session_name, clients)?;
+
+        Ok(secret)
+    }
+
+    fn get_client_secret(&self, session_name: &str, client_name: &str) -> Result<Option<String>> {
+        let secret = self.get_record(&store::client_secret_key(session_name, client_name))?;
+        Ok(secret.and_then(|secret| secret.as_str().map(String::from)))
+    }
+
+    fn get_clients(&self, session_name: &str) -> Result<Vec<(String, ClientStatus)>> {
+        self.get_client_list(session_name)?
+            .into_iter()
+            .map(|(name, status)| Ok((name, ClientStatus::parse(status.as_str().unwrap_or_default())?)))
+            .collect()
+    }
+
+    fn get_client_status(&self, session_name: &str, client_name: &str) -> Result<Option<ClientStatus>> {
+        let clients = self.get_client_list(session_name)?;
+        clients.get(client_name)
+            .and_then(Value::as_str)
+            .map(ClientStatus::parse)
+            .transpose()
+    }
+
+    fn set_client_status(&self, session_name: &str, client_name: &str, status: ClientStatus) -> Result<()> {
+        let mut clients = self.get_client_list(session_name)?;
+        clients.insert(client_name.into(), json!(status.as_str()));
+        self.set_client_list(session_name, clients)
+    }
+
+    fn remove_client(&self, session_name: &str, client_name: &str) -> Result<()> {
+        self.delete(&store::client_secret_key(session_name, client_name))?;
+        self.delete(&Mailbox::Client { session_name, client_name }.key())?;
+        self.unlist_client(session_name, client_name)
+    }
+
+    fn expire_client(&self, session_name: &str, client_name: &str, seconds: i64) -> Result<()> {
+        let expires_at = store::now() + seconds;
+
+        self.touch(&store::client_secret_key(session_name, client_name), expires_at)?;
+        self.touch(&Mailbox::Client { session_name, client_name }.key(), expires_at)?;
+        self.unlist_client(session_name, client_name)
+    }
+
+    fn set_client_address(&self, session_name: &str, client_name: &str, address: &str) -> Result<()> {
+        let key = store::client_address_key(session_name);
+        let mut addresses = self.get_object(&key)?;
+        addresses.insert(client_name.into(), json!(address));
+
+        self.set_record(&key, &Value::Object(addresses), Some(store::now() + self.config.session_ttl_seconds))
+    }
+
+    fn get_client_address(&self, session_name: &str, client_name: &str) -> Result<Option<String>> {
+        let addresses = self.get_object(&store::client_address_key(session_name))?;
+        Ok(addresses.get(client_name).and_then(Value::as_str).map(String::from))
+    }
+
+    fn add_ban(&self, session_name: &str, entry: &str) -> Result<()> {
+        // An object rather than an array, so it works like a set
+        let key = store::ban_list_key(session_name);
+        let mut bans = self.get_object(&key)?;
+        bans.insert(entry.into(), json!(true));
+
+        self.set_record(&key, &Value::Object(bans), Some(store::now() + self.config.session_ttl_seconds))
+    }
+
+    fn is_banned(&self, session_name: &str, entry: &str) -> Result<bool> {
+        Ok(self.get_object(&store::ban_list_key(session_name))?.contains_key(entry))
+    }
+
+    fn enqueue_waiting(&self, session_name: &str, client_name: &str, rtc_offer: &str) -> Result<usize> {
+        let mut waiting = self.get_waiting_list(session_name)?;
+        waiting.push(json!([client_name, rtc_offer]));
+        let position = waiting.len();
+
+        self.set_waiting_list(session_name, waiting)?;
+        Ok(position)
+    }
+
+    fn dequeue_waiting(&self, session_name: &str) -> Result<Option<(String, String)>> {
+        let mut waiting = self.get_waiting_list(session_name)?;
+        if waiting.is_empty() {
+            return Ok(None);
+        }
+
+        let next = waiting.remove(0);
+        self.set_waiting_list(session_name, waiting)?;
+
+        match (next[0].as_str(), next[1].as_str()) {
+            (Some(client_name), Some(rtc_offer)) => Ok(Some((client_name.into(), rtc_offer.into()))),
+            _ => Err(anyhow!("Invalid waiting list entry")),
+        }
+    }
+
+    fn remove_waiting(&self, session_name: &str, client_name: &str) -> Result<()> {
+        let mut waiting = self.get_waiting_list(session_name)?;
+        let before = waiting.len();
+        waiting.retain(|entry| entry[0].as_str() != Some(client_name));
+
+        if waiting.len() != before {
+            self.set_waiting_list(session_name, waiting)?;
+        }
+
+        Ok(())
+    }
+
+    fn increment_counter(&self, key: &str, window_seconds: i64) -> Result<i64> {
+        // Counters are { count, window_ends }, so the window doesn't move every time we write
+        let counter = self.get_record(key)?;
+        let count = counter.as_ref().and_then(|counter| counter["count"].as_i64()).unwrap_or(0) + 1;
+        let window_ends = counter.as_ref()
+            .and_then(|counter| counter["window_ends"].as_i64())
+            .unwrap_or_else(|| store::now() + window_seconds);
+
+        self.set_record(key, &json!({ "count": count, "window_ends": window_ends }), Some(window_ends))?;
+        Ok(count)
+    }
+
+    fn get_counter(&self, key: &str) -> Result<i64> {
+        let counter = self.get_record(key)?;
+        Ok(counter.and_then(|counter| counter["count"].as_i64()).unwrap_or(0))
+    }
+
+    fn extend_counter(&self, key: &str, expires_at: i64) -> Result<()> {
+        let Some(counter) = self.get_record(key)? else {
+            return Ok(());
+        };
+
+        let window_ends = counter["window_ends"].as_i64().unwrap_or_default().max(expires_at);
+        self.set_record(key, &json!({ "count": counter["count"], "window_ends": window_ends }), Some(window_ends))
+    }
+
+    fn deny_token(&self, token_id: &str, from: i64) -> Result<()> {
+        let expires_at = store::now() + self.config.token_ttl_seconds;
+        self.set_record(&store::denied_token_key(token_id), &json!(from), Some(expires_at))
+    }
+
+    fn get_token_denial(&self, token_id: &str) -> Result<Option<i64>> {
+        Ok(self.get_record(&store::denied_token_key(token_id))?.and_then(|from| from.as_i64()))
+    }
+
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn push_message(&self, mailbox: &Mailbox, message: &Value) -> Result<()> {
+        let key = mailbox.key();
+
+        let mut record = self.get_mailbox(&key)?;
+        let id = record["next_id"].as_u64().unwrap_or(0) + 1;
+        record["next_id"] = json!(id);
+        if let Some(messages) = record["messages"].as_array_mut() {
+            messages.push(json!([id, message]));
+        }
+
+        self.set_record(&key, &record, Some(store::now() + self.config.session_ttl_seconds))
+    }
+
+    fn mailbox_length(&self, mailbox: &Mailbox) -> Result<usize> {
+        Ok(self.get_mailbox(&mailbox.key())?["messages"].as_array().map_or(0, Vec::len))
+    }
+
+    fn trim_mailbox(&self, mailbox: &Mailbox, keep: usize) -> Result<usize> {
+        let key = mailbox.key();
+        let Some(mut record) = self.get_record(&key)? else {
+            return Ok(0);
+        };
+        let Some(messages) = record["messages"].as_array_mut() else {
+            return Ok(0);
+        };
+
+        let dropped = messages.len().saturating_sub(keep);
+        if dropped == 0 {
+            return Ok(0);
+        }
+
+        messages.drain(..dropped);
+        self.set_record(&key, &record, Some(store::now() + self.config.session_ttl_seconds))?;
+        Ok(dropped)
+    }
+
+    fn read_messages(&self, mailbox: &Mailbox, since: Option<&str>, wait_seconds: i64) -> Result<Vec<MailboxMessage>> {
+        let key = mailbox.key();
+        let since = since.map(store::parse_sequence_cursor).transpose()?.unwrap_or(0);
+        let start = Instant::now();
+        let timeout = Duration::from_secs(wait_seconds.max(0) as u64);
+
+        // No blocking reads here, so check back every so often until something shows up
+        loop {
+            let record = self.get_mailbox(&key)?;
+            let messages: Vec<MailboxMessage> = Self::mailbox_entries(&record)
+                .filter(|(id, _)| *id > since)
+                .take(self.config.message_batch_size)
+                .map(|(id, message)| MailboxMessage { id: id.to_string(), message: message.clone() })
+                .collect();
+
+            if !messages.is_empty() || start.elapsed() >= timeout {
+                return Ok(messages);
+            }
+
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn ack_messages(&self, mailbox: &Mailbox, cursor: &str) -> Result<()> {
+        let key = mailbox.key();
+        let cursor = store::parse_sequence_cursor(cursor)?;
+
+        let mut record = self.get_mailbox(&key)?;
+        let remaining: Vec<Value> = Self::mailbox_entries(&record)
+            .filter(|(id, _)| *id > cursor)
+            .map(|(id, message)| json!([id, message]))
+            .collect();
+
+        // Nothing to acknowledge, don't bother writing
+        if remaining.len() == record["messages"].as_array().map_or(0, Vec::len) {
+            return Ok(());
+        }
+
+        record["messages"] = Value::Array(remaining);
+        self.set_record(&key, &record, Some(store::now() + self.config.session_ttl_seconds))
+    }
+}
@@ -1,52 +1,152 @@
+use std::collections::HashMap;
+
 use anyhow::{anyhow, Result};
-use http::{Method, HeaderValue};
+use http::Method;
 use serde_json::{Value, json};
-use spin_sdk::{
-    http::{Request, Response},
-    http_component,
-};
+use spin_sdk::http::{Request, Response};
+
+pub mod config;
+use config::Config;
+
+pub mod error;
+use error::{ApiError, ApiResult};
+
+pub mod message;
+use message::SignalMessage;
 
+pub mod store;
+use store::{JoinOutcome, Mailbox, MailboxMessage, Occupancy, PasswordCheck, SessionOptions, SignalingStore};
+
+#[cfg(target_arch = "wasm32")]
 mod redis_helper;
-use redis_helper::RedisHelper;
+#[cfg(target_arch = "wasm32")]
+mod kv_helper;
+// Only for running the server off of Spin, e.g. in tests, so it stays out of the component
+#[cfg(not(target_arch = "wasm32"))]
+pub mod memory_store;
 
 mod req_helpers;
 use req_helpers::*;
 
+mod password;
+use password::hash_password;
+
+pub mod router;
+use router::{Params, Router};
+
+mod cors;
+use cors::cors;
+
+mod auth;
+use auth::{json_secret, query_secret, query_secrets};
+
+mod token;
+
+mod rate_limit;
+use rate_limit::{limit_joins, limit_requests, limit_session_creation};
+
+mod sse;
+use sse::{event_stream, resume_cursor, wants_event_stream};
+
 mod random_util;
 use random_util::generate_name;
 
+/// Page size for the public session list, if the client doesn't ask for one
+const DEFAULT_SESSION_PAGE_SIZE: usize = 20;
+/// Largest page of public sessions we'll hand out at once
+const MAX_SESSION_PAGE_SIZE: usize = 100;
+/// Largest max_clients a host can ask for
+const MAX_CLIENTS_LIMIT: u64 = 1000;
+/// Longest host or client name, in characters
+const MAX_NAME_LENGTH: usize = 64;
+/// How soon to poll again while messages are flowing, e.g. someone's joining
+const ACTIVE_POLL_MS: u64 = 250;
+/// How soon to poll again when nothing's happened lately
+const IDLE_POLL_MS: u64 = 3000;
 
 /// A simple Spin HTTP component.
-#[http_component]
+#[cfg(target_arch = "wasm32")]
+#[spin_sdk::http_component]
 fn handle_rust_signaling(req: Request) -> Result<Response> {
-    let res = match (req.method(), req.uri().path()) {
-        (&Method::GET, "/") => Ok(http::Response::builder().status(200).body(Some(include_str!("./index.html").into()))?),
-        (&Method::GET, "/test") => test_route(),
+    let config = match Config::load() {
+        Ok(config) => config,
+        // Logged on the way out, the caller can't do anything about it anyway
+        Err(e) => return ApiError::Internal(e).into_response(),
+    };
 
-        // Start a session
-        (&Method::POST, "/host") => post_host_session(req),
-        // Receive messages from a client
-        (&Method::GET, "/host/messages") => get_receive_host_messages(&req),
-        
-        (&Method::POST, "/join/response") => post_send_join_responses(&req),
+    let store = match store::open(&config) {
+        Ok(store) => store,
+        Err(e) => return ApiError::StoreUnavailable(e).into_response(),
+    };
+
+    handle_request(store.as_ref(), &config, req)
+}
+
+/// Routes a request to its handler, independent of the Spin runtime so it can be driven
+/// with any store, e.g. a MemoryStore on the host
+pub fn handle_request(store: &dyn SignalingStore, config: &Config, req: Request) -> Result<Response> {
+    match router().handle(store, config, &req) {
+        Ok(res) => Ok(res),
+        Err(e) => e.into_response(),
+    }
+}
+
+fn router() -> Router {
+    Router::new()
+        .wrap(cors)
+        .wrap(query_secrets)
+        .route(Method::GET, "/", |_, _, _| {
+            http::Response::builder().status(200).body(Some(include_str!("./index.html").into())).map_err(ApiError::from)
+        })
+        .route(Method::GET, "/test", |store, _, _| test_route(store)).with(limit_requests)
 
+        // Start a session
+        .route(Method::POST, "/sessions", post_host_session).with(limit_session_creation)
         // Get the list of public sessions
-        (&Method::GET, "/sessions") => get_session_list(&req),
+        .route(Method::GET, "/sessions", get_session_list).with(limit_requests)
+        // End a session
+        .route(Method::DELETE, "/sessions/{session_name}", delete_host_session).with(limit_requests)
+        // Receive messages from clients
+        .route(Method::GET, "/sessions/{session_name}/messages", get_receive_host_messages).with(limit_requests)
+        // Acknowledge messages from clients without waiting for more
+        .route(Method::POST, "/sessions/{session_name}/messages/ack", post_ack_host_messages).with(limit_requests)
+        // Keep the session alive without polling
+        .route(Method::POST, "/sessions/{session_name}/heartbeat", post_host_heartbeat).with(limit_requests)
+
         // Start joining a session
-        (&Method::POST, "/join") => join_session(&req),
-        // Send messages to the host
-        (&Method::POST, "/join/candidates") => post_send_join_candidates(&req),
+        .route(Method::POST, "/sessions/{session_name}/clients", join_session).with(limit_joins)
+        // Let a client in, or turn them away
+        .route(Method::POST, "/sessions/{session_name}/clients/{client_name}/decision", post_join_decision).with(limit_requests)
+        // Throw a client out, and maybe keep them out
+        .route(Method::POST, "/sessions/{session_name}/clients/{client_name}/kick", post_kick_client).with(limit_requests)
+        // Host sends messages to a client
+        .route(Method::POST, "/sessions/{session_name}/clients/{client_name}/responses", post_send_join_responses).with(limit_requests)
+        // Client sends candidates to the host
+        .route(Method::POST, "/sessions/{session_name}/clients/{client_name}/candidates", post_send_join_candidates).with(limit_requests)
+        // Host sends candidates to a client
+        .route(Method::POST, "/sessions/{session_name}/clients/{client_name}/host_candidates", post_send_host_candidates).with(limit_requests)
         // Receive messages from the host
-        (&Method::GET, "/join/messages") => get_receive_join_responses(&req),
-        
-
-        _ => Ok(http::Response::builder().status(404).body(Some("Not found".into()))?)
-    };
-
-    res.map(|mut res| {
-        res.headers_mut().append("Access-Control-Allow-Origin", HeaderValue::from_str("*").unwrap());
-        res
-    })
+        .route(Method::GET, "/sessions/{session_name}/clients/{client_name}/messages", get_receive_join_responses).with(limit_requests)
+        // Acknowledge messages from the host without waiting for more
+        .route(Method::POST, "/sessions/{session_name}/clients/{client_name}/messages/ack", post_ack_join_responses).with(limit_requests)
+        // Leave a session
+        .route(Method::POST, "/sessions/{session_name}/clients/{client_name}/leave", post_leave_session).with(limit_requests)
+
+        // The original flat routes, with everything in the query or body
+        .route(Method::POST, "/host", post_host_session).with(limit_session_creation)
+        .route(Method::DELETE, "/host", delete_host_session).with(limit_requests)
+        .route(Method::GET, "/host/messages", get_receive_host_messages).with(limit_requests)
+        .route(Method::POST, "/host/messages/ack", post_ack_host_messages).with(limit_requests)
+        .route(Method::POST, "/host/heartbeat", post_host_heartbeat).with(limit_requests)
+        .route(Method::POST, "/host/decision", post_join_decision).with(limit_requests)
+        .route(Method::POST, "/host/kick", post_kick_client).with(limit_requests)
+        .route(Method::POST, "/host/candidates", post_send_host_candidates).with(limit_requests)
+        .route(Method::POST, "/join/response", post_send_join_responses).with(limit_requests)
+        .route(Method::POST, "/join", join_session).with(limit_joins)
+        .route(Method::POST, "/join/candidates", post_send_join_candidates).with(limit_requests)
+        .route(Method::GET, "/join/messages", get_receive_join_responses).with(limit_requests)
+        .route(Method::POST, "/join/messages/ack", post_ack_join_responses).with(limit_requests)
+        .route(Method::POST, "/join/leave", post_leave_session).with(limit_requests)
 }
 
 /*
@@ -63,166 +163,482 @@ fn handle_rust_signaling(req: Request) -> Result<Response> {
     - One for hosts to poll for joiners!
 */
 
-fn post_host_session(req: Request) -> Result<Response> {
-    // Retrieve variables
-    let body = req.body().as_ref().ok_or_else(|| anyhow!("Invalid body"))?;
-    let body = std::str::from_utf8(body).map_err(|_| anyhow!("Invalid body"))?;
-    let body: Value = serde_json::from_str(body).map_err(|_| anyhow!("Invalid body"))?;
+pub fn post_host_session(store: &dyn SignalingStore, req: &Request, params: &Params) -> ApiResult<Response> {
+    let body = get_json_body(req, params)?;
 
     let is_public = required_json_bool(&body, "public")?;
     let host_name = required_json_str(&body, "host_name")?;
+    check_name("host_name", host_name)?;
+    let max_clients = optional_json_u64(&body, "max_clients")?;
+    let waiting_list = optional_json_bool(&body, "waiting_list")?.unwrap_or(false);
+    let password = optional_json_str(&body, "password")?;
+
+    if max_clients.is_some_and(|max_clients| !(1..=MAX_CLIENTS_LIMIT).contains(&max_clients)) {
+        return Err(ApiError::InvalidParameter(format!("max_clients must be between 1 and {MAX_CLIENTS_LIMIT}")));
+    }
+
+    if password.is_some_and(str::is_empty) {
+        return Err(ApiError::InvalidParameter("password can't be empty".into()));
+    }
+
+    let options = SessionOptions {
+        max_clients: max_clients.map(|max_clients| max_clients as usize),
+        waiting_list,
+        password_hash: password.map(hash_password),
+    };
     
-    // Generate a name, ensuring we don't already have such a session
+    // Generate a name and register the session under it, trying again if it's already taken
     let mut safety = 0;
-    let session_name = loop {
+    let (session_name, host_secret) = loop {
         let ret = generate_name();
-        if !RedisHelper::has_session(&ret)? {
-            break ret;
+        let host_secret = store.create_session(&ret, is_public, host_name, &options)?;
+
+        if let Some(host_secret) = host_secret {
+            break (ret, host_secret);
         }
 
         safety += 1;
         if safety > 1000 {
-            return Err(anyhow!("Failed to generate session name"));
+            return Err(ApiError::Internal(anyhow!("Failed to generate session name")));
         }
     };
 
-    // Register the session
-    let host_secret = RedisHelper::register_session(&session_name, is_public, host_name)
-        .map_err(|_| anyhow!("Failed to register session"))?;
-
     // Return the session name to the requestor
     let res_body = json!({
         "success": true,
         "session_name": session_name,
         "host_secret": host_secret,
+        "max_clients": options.max_clients,
+        "waiting_list": options.waiting_list,
+        "password_protected": options.password_hash.is_some(),
     });
 
     http::Response::builder()
         .status(200)
         .body(Some(res_body.to_string().into()))
-        .map_err(|_| anyhow!("Failed to build response"))
+        .map_err(ApiError::from)
 }
 
-fn get_receive_host_messages(req: &Request) -> Result<Response> {
-    let query = parse_query(req)?;
+pub fn get_receive_host_messages(store: &dyn SignalingStore, req: &Request, params: &Params) -> ApiResult<Response> {
+    let query = parse_query_with_params(req, params)?;
     let session_name = required_query(&query, "session_name")?;
-    let host_secret = required_query(&query, "host_secret")?;
+    let host_secret = query_secret(req, &query, "host_secret")?;
 
-    if !RedisHelper::authenticate_host_message(session_name, host_secret)? {
-        return unauthenticated();
+    if !store.authenticate_host_message(session_name, host_secret)? {
+        return Err(ApiError::Unauthenticated);
+    }
+    if req.method() == Method::HEAD {
+        return head_response();
     }
 
-    let messages = RedisHelper::get_messages_for_host(session_name)?;
+    // A host that's polling is still around, so this doubles as a heartbeat
+    let Some(expires_at) = store.renew_session(session_name)? else {
+        return Err(ApiError::Unauthenticated);
+    };
+
+    if wants_event_stream(req) {
+        let since = resume_cursor(req, &query);
+        let wait = store.config().poll_timeout_seconds;
+        return event_stream(store.config(), since, |since| store.get_messages_for_host(session_name, since, wait));
+    }
+
+    let since = query.get("since").map(String::as_str);
+    let wait = poll_wait(store.config(), &query)?;
+    let messages = store.get_messages_for_host(session_name, since, wait)?;
+
+    let mut res_body = messages_body(store, &Mailbox::Host { session_name }, messages, since)?;
+    res_body["expires_at"] = json!(expires_at);
+    res_body["occupancy"] = occupancy_body(store.get_occupancy(session_name)?);
+    if let Some(host_secret) = store.renew_secret(host_secret, session_name, None)? {
+        res_body["host_secret"] = json!(host_secret);
+    }
 
-    let res = json!(messages);
     http::Response::builder()
         .status(200)
-        .body(Some(res.to_string().into()))
-        .map_err(|_| anyhow!("Failed to build response"))
+        .body(Some(res_body.to_string().into()))
+        .map_err(ApiError::from)
 }
 
-/// Send messages to a client
-fn post_send_join_responses(req: &Request) -> Result<Response> {
-    // Retrieve variables
-    let body = get_json_body(req)?;
-    
+/// Host is done with the session, clean up after it and let the clients know
+pub fn delete_host_session(store: &dyn SignalingStore, req: &Request, params: &Params) -> ApiResult<Response> {
+    let query = parse_query_with_params(req, params)?;
+    let session_name = required_query(&query, "session_name")?;
+    let host_secret = query_secret(req, &query, "host_secret")?;
+
+    if !store.authenticate_host_message(session_name, host_secret)? {
+        return Err(ApiError::Unauthenticated);
+    }
+
+    store.close_session(session_name)?;
+
+    http::Response::builder()
+        .status(200)
+        .body(None)
+        .map_err(ApiError::from)
+}
+
+/// Host is still here, extend the session (and its clients) for another TTL
+pub fn post_host_heartbeat(store: &dyn SignalingStore, req: &Request, params: &Params) -> ApiResult<Response> {
+    let body = get_json_body(req, params)?;
+
+    let session_name = required_json_str(&body, "session_name")?;
+    let host_secret = json_secret(req, &body, "host_secret")?;
+
+    if !store.authenticate_host_message(session_name, host_secret)? {
+        return Err(ApiError::Unauthenticated);
+    }
+
+    let Some(expires_at) = store.renew_session(session_name)? else {
+        return Err(ApiError::Unauthenticated);
+    };
+
+    let mut res_body = json!({
+        "success": true,
+        "expires_at": expires_at,
+        "occupancy": occupancy_body(store.get_occupancy(session_name)?),
+    });
+    // Tokens don't last forever, so keep the host supplied with one that will outlast the session
+    if let Some(host_secret) = store.renew_secret(host_secret, session_name, None)? {
+        res_body["host_secret"] = json!(host_secret);
+    }
+
+    http::Response::builder()
+        .status(200)
+        .body(Some(res_body.to_string().into()))
+        .map_err(ApiError::from)
+}
+
+/// Host is done with messages up to a cursor
+pub fn post_ack_host_messages(store: &dyn SignalingStore, req: &Request, params: &Params) -> ApiResult<Response> {
+    let body = get_json_body(req, params)?;
+
+    let session_name = required_json_str(&body, "session_name")?;
+    let host_secret = json_secret(req, &body, "host_secret")?;
+    let cursor = required_json_str(&body, "cursor")?;
+
+    if !store.authenticate_host_message(session_name, host_secret)? {
+        return Err(ApiError::Unauthenticated);
+    }
+
+    store.ack_messages(&Mailbox::Host { session_name }, cursor)?;
+
+    http::Response::builder()
+        .status(200)
+        .body(None)
+        .map_err(ApiError::from)
+}
+
+/// Host decides whether a pending client gets to join
+pub fn post_join_decision(store: &dyn SignalingStore, req: &Request, params: &Params) -> ApiResult<Response> {
+    let body = get_json_body(req, params)?;
+
     let session_name = required_json_str(&body, "session_name")?;
     let client_name = required_json_str(&body, "client_name")?;
-    let host_secret = required_json_str(&body, "host_secret")?;
-    let messages = &body["messages"];
+    let host_secret = json_secret(req, &body, "host_secret")?;
+    let accept = required_json_bool(&body, "accept")?;
+    let reason = optional_json_str(&body, "reason")?;
+
+    SignalMessage::JoinRejected { reason: reason.map(String::from) }.check_limits(store.config())?;
 
-    if !RedisHelper::authenticate_host_message(session_name, host_secret)? {
-        return unauthenticated();
+    if !store.authenticate_host_message(session_name, host_secret)? {
+        return Err(ApiError::Unauthenticated);
     }
 
-    RedisHelper::push_message_to_client(session_name, client_name, messages)?;
+    let decided = if accept {
+        store.accept_client(session_name, client_name)?
+    } else {
+        store.reject_client(session_name, client_name, reason)?
+    };
+
+    if !decided {
+        return Err(ApiError::NoPendingJoin);
+    }
 
     http::Response::builder()
         .status(200)
         .body(None)
-        .map_err(|_| anyhow!("Failed to build response"))
+        .map_err(ApiError::from)
 }
 
-fn get_session_list(_req: &Request) -> Result<Response> {
-    todo!()
+/// Host throws a client out
+pub fn post_kick_client(store: &dyn SignalingStore, req: &Request, params: &Params) -> ApiResult<Response> {
+    let body = get_json_body(req, params)?;
+
+    let session_name = required_json_str(&body, "session_name")?;
+    let client_name = required_json_str(&body, "client_name")?;
+    let host_secret = json_secret(req, &body, "host_secret")?;
+    let reason = optional_json_str(&body, "reason")?;
+    let ban = optional_json_bool(&body, "ban")?.unwrap_or(false);
+    let ban_address = optional_json_bool(&body, "ban_address")?.unwrap_or(false);
+
+    SignalMessage::Kicked { reason: reason.map(String::from) }.check_limits(store.config())?;
+
+    if !store.authenticate_host_message(session_name, host_secret)? {
+        return Err(ApiError::Unauthenticated);
+    }
+
+    if !store.kick_client(session_name, client_name, reason, ban, ban_address)? {
+        return Err(ApiError::NoSuchClient);
+    }
+
+    http::Response::builder()
+        .status(200)
+        .body(None)
+        .map_err(ApiError::from)
 }
 
-/// A client is initiating the join process
-fn join_session(req: &Request) -> Result<Response> {
+/// Send messages to a client
+pub fn post_send_join_responses(store: &dyn SignalingStore, req: &Request, params: &Params) -> ApiResult<Response> {
     // Retrieve variables
-    let body = get_json_body(req)?;
+    let body = get_json_body(req, params)?;
     
     let session_name = required_json_str(&body, "session_name")?;
     let client_name = required_json_str(&body, "client_name")?;
-    let rtc_offer = required_json_str(&body, "rtc_offer")?;
+    let host_secret = json_secret(req, &body, "host_secret")?;
+
+    // One message, or a list of them to send in order
+    let messages = match &body["messages"] {
+        Value::Array(messages) => messages.clone(),
+        message => vec![message.clone()],
+    };
 
-    let client_secret = RedisHelper::initiate_join(session_name, client_name, rtc_offer)?;
+    // Check everything before sending anything
+    let mut parsed = Vec::with_capacity(messages.len());
+    for message in messages {
+        let message = SignalMessage::parse(message)?;
+
+        if !message.is_from_host() {
+            return Err(ApiError::InvalidMessage("Hosts can't send that type of message".into()));
+        }
+        message.check_limits(store.config())?;
+
+        parsed.push(message);
+    }
+
+    if !store.authenticate_host_message(session_name, host_secret)? {
+        return Err(ApiError::Unauthenticated);
+    }
+
+    if store.get_client_status(session_name, client_name)?.is_none() {
+        return Err(ApiError::NoSuchClient);
+    }
+
+    let dropped = store.push_messages_to_client(session_name, client_name, &parsed)?;
+
+    http::Response::builder()
+        .status(200)
+        .body(Some(json!({ "success": true, "dropped": dropped }).to_string().into()))
+        .map_err(ApiError::from)
+}
+
+/// The host is sending ICE candidates to a client, the same way clients send theirs
+pub fn post_send_host_candidates(store: &dyn SignalingStore, req: &Request, params: &Params) -> ApiResult<Response> {
+    let body = get_json_body(req, params)?;
+
+    let session_name = required_json_str(&body, "session_name")?;
+    let client_name = required_json_str(&body, "client_name")?;
+    let host_secret = json_secret(req, &body, "host_secret")?;
+    let messages = candidate_messages(store.config(), &body, None)?;
+
+    if !store.authenticate_host_message(session_name, host_secret)? {
+        return Err(ApiError::Unauthenticated);
+    }
+
+    if store.get_client_status(session_name, client_name)?.is_none() {
+        return Err(ApiError::NoSuchClient);
+    }
+
+    // These wait for the answer if it hasn't gone out yet
+    let dropped = store.host_ice_candidates(session_name, client_name, &messages)?;
+
+    http::Response::builder()
+        .status(200)
+        .body(Some(json!({ "success": true, "dropped": dropped }).to_string().into()))
+        .map_err(ApiError::from)
+}
+
+/// Lists public sessions, a page at a time
+pub fn get_session_list(store: &dyn SignalingStore, req: &Request, params: &Params) -> ApiResult<Response> {
+    let query = parse_query_with_params(req, params)?;
+    let prefix = query.get("prefix").map(String::as_str);
+    let cursor = query.get("cursor").map(String::as_str);
+    let min_age = optional_query_parsed::<i64>(&query, "min_age")?;
+    let limit = optional_query_parsed::<usize>(&query, "limit")?
+        .unwrap_or(DEFAULT_SESSION_PAGE_SIZE)
+        .clamp(1, MAX_SESSION_PAGE_SIZE);
+
+    let (sessions, next_cursor) = store.list_public_sessions(prefix, min_age, cursor, limit)?;
+
+    let sessions = sessions.into_iter().map(|session| Ok(json!({
+        "occupancy": occupancy_body(store.get_occupancy(&session.session_name)?),
+        "session_name": session.session_name,
+        "host_name": session.host_name,
+        "created_at": session.created_at,
+    }))).collect::<ApiResult<Vec<Value>>>()?;
 
     let res_body = json!({
         "success": true,
-        "client_secret": client_secret,
+        "sessions": sessions,
+        "next_cursor": next_cursor,
     });
 
     http::Response::builder()
         .status(200)
         .body(Some(res_body.to_string().into()))
-        .map_err(|_| anyhow!("Failed to build response"))
+        .map_err(ApiError::from)
 }
 
-/// A client is sending a message to the host
-fn post_send_join_candidates(req: &Request) -> Result<Response> {
-    let body = get_json_body(req)?;
+/// A client is initiating the join process
+pub fn join_session(store: &dyn SignalingStore, req: &Request, params: &Params) -> ApiResult<Response> {
+    // Retrieve variables
+    let body = get_json_body(req, params)?;
     
     let session_name = required_json_str(&body, "session_name")?;
     let client_name = required_json_str(&body, "client_name")?;
-    let client_secret = required_json_str(&body, "client_secret")?;
-    let candidates = &body["candidates"];
+    let rtc_offer = required_json_str(&body, "rtc_offer")?;
+    let password = optional_json_str(&body, "password")?;
+    let address = client_address(req, &store.config().client_ip_header);
 
-    let candidates = candidates.as_array()
-        .map(|candidates| 
-            candidates
-                .iter()
-                .map(|candidate| candidate.as_str())
-                .collect::<Option<Vec<_>>>()
-        )
-        // TODO not have this be an Option<Option<_>>?
-        .ok_or_else(|| anyhow!("Candidates must be an array of string canidates"))?
-        .ok_or_else(|| anyhow!("Candidates must be an array of string canidates"))?;
+    // The host gets all of it as a start_join, which has to fit as a whole
+    check_name("client_name", client_name)?;
+    SignalMessage::StartJoin { client_name: client_name.into(), client_offer: rtc_offer.into() }
+        .check_limits(store.config())?;
 
-    if !RedisHelper::authenticate_client_message(session_name, client_name, client_secret)? {
-        return unauthenticated();
+    if store.is_join_banned(session_name, client_name, address.as_deref())? {
+        return Err(ApiError::Banned);
     }
 
-    RedisHelper::client_ice_candidate(session_name, client_name, candidates)?;
+    // Before anything gets queued up for the host
+    match store.check_join_password(session_name, password, address.as_deref())? {
+        PasswordCheck::Correct => {},
+        PasswordCheck::Wrong => return Err(ApiError::WrongPassword),
+        PasswordCheck::Throttled => {
+            return Err(ApiError::TooManyRequests { retry_after: store::FAILED_JOIN_WINDOW_SECONDS });
+        },
+    }
+
+    let res_body = match store.initiate_join(session_name, client_name, rtc_offer, address.as_deref())? {
+        JoinOutcome::Pending(client_secret) => json!({
+            "success": true,
+            "client_secret": client_secret,
+            "waiting": false,
+        }),
+        JoinOutcome::Waiting { client_secret, position } => json!({
+            "success": true,
+            "client_secret": client_secret,
+            "waiting": true,
+            "position": position,
+        }),
+        JoinOutcome::Full => return Err(ApiError::SessionFull),
+    };
 
     http::Response::builder()
         .status(200)
-        .body(None)
-        .map_err(|_| anyhow!("Failed to build response"))
+        .body(Some(res_body.to_string().into()))
+        .map_err(ApiError::from)
 }
 
-fn get_receive_join_responses(req: &Request) -> Result<Response> {
-    let query = parse_query(req)?;
+/// A client is sending ICE candidates to the host, a batch of them, one at a time as they're
+/// gathered, and/or the end of them
+pub fn post_send_join_candidates(store: &dyn SignalingStore, req: &Request, params: &Params) -> ApiResult<Response> {
+    let body = get_json_body(req, params)?;
+    
+    let session_name = required_json_str(&body, "session_name")?;
+    let client_name = required_json_str(&body, "client_name")?;
+    let client_secret = json_secret(req, &body, "client_secret")?;
+    let messages = candidate_messages(store.config(), &body, Some(client_name))?;
+
+    if !store.authenticate_client_message(session_name, client_name, client_secret)? {
+        return Err(ApiError::Unauthenticated);
+    }
+
+    // Nothing goes to the host until they've let us in, so early candidates wait until then
+    let dropped = store.client_ice_candidates(session_name, client_name, &messages)?;
+
+    http::Response::builder()
+        .status(200)
+        .body(Some(json!({ "success": true, "dropped": dropped }).to_string().into()))
+        .map_err(ApiError::from)
+}
+
+pub fn get_receive_join_responses(store: &dyn SignalingStore, req: &Request, params: &Params) -> ApiResult<Response> {
+    let query = parse_query_with_params(req, params)?;
     let session_name = required_query(&query, "session_name")?;
     let client_name = required_query(&query, "client_name")?;
-    let client_secret = required_query(&query, "client_secret")?;
+    let client_secret = query_secret(req, &query, "client_secret")?;
 
-    if !RedisHelper::authenticate_client_message(session_name, client_name, client_secret)? {
-        return unauthenticated();
+    if !store.authenticate_client_message(session_name, client_name, client_secret)? {
+        return Err(ApiError::Unauthenticated);
+    }
+    if req.method() == Method::HEAD {
+        return head_response();
     }
 
-    let messages = RedisHelper::get_messages_for_client(session_name, client_name)?;
+    if wants_event_stream(req) {
+        let since = resume_cursor(req, &query);
+        let wait = store.config().poll_timeout_seconds;
+        return event_stream(store.config(), since, |since| store.get_messages_for_client(session_name, client_name, since, wait));
+    }
+
+    let since = query.get("since").map(String::as_str);
+    let wait = poll_wait(store.config(), &query)?;
+    let messages = store.get_messages_for_client(session_name, client_name, since, wait)?;
+    let mut res_body = messages_body(store, &Mailbox::Client { session_name, client_name }, messages, since)?;
+    if let Some(client_secret) = store.renew_secret(client_secret, session_name, Some(client_name))? {
+        res_body["client_secret"] = json!(client_secret);
+    }
 
     http::Response::builder()
         .status(200)
-        .body(Some(json!(messages).to_string().into()))
-        .map_err(|_| anyhow!("Failed to build response"))
+        .body(Some(res_body.to_string().into()))
+        .map_err(ApiError::from)
+}
+
+/// Client is done with messages up to a cursor
+pub fn post_ack_join_responses(store: &dyn SignalingStore, req: &Request, params: &Params) -> ApiResult<Response> {
+    let body = get_json_body(req, params)?;
+
+    let session_name = required_json_str(&body, "session_name")?;
+    let client_name = required_json_str(&body, "client_name")?;
+    let client_secret = json_secret(req, &body, "client_secret")?;
+    let cursor = required_json_str(&body, "cursor")?;
+
+    if !store.authenticate_client_message(session_name, client_name, client_secret)? {
+        return Err(ApiError::Unauthenticated);
+    }
+
+    store.ack_messages(&Mailbox::Client { session_name, client_name }, cursor)?;
+
+    http::Response::builder()
+        .status(200)
+        .body(None)
+        .map_err(ApiError::from)
+}
+
+/// Client is done with the session, clean up after them and let the host know
+pub fn post_leave_session(store: &dyn SignalingStore, req: &Request, params: &Params) -> ApiResult<Response> {
+    let body = get_json_body(req, params)?;
+
+    let session_name = required_json_str(&body, "session_name")?;
+    let client_name = required_json_str(&body, "client_name")?;
+    let client_secret = json_secret(req, &body, "client_secret")?;
+
+    if !store.authenticate_client_message(session_name, client_name, client_secret)? {
+        return Err(ApiError::Unauthenticated);
+    }
+
+    store.leave_session(session_name, client_name)?;
+
+    http::Response::builder()
+        .status(200)
+        .body(None)
+        .map_err(ApiError::from)
 }
 
 /// Just a route to test connecting to our backing store
-fn test_route() -> Result<Response> {
-    let count = RedisHelper::get_test_value()? + 1;
-    RedisHelper::set_test_value(count)?;
+fn test_route(store: &dyn SignalingStore) -> ApiResult<Response> {
+    let count = store.get_test_value()? + 1;
+    store.set_test_value(count)?;
 
     let name = generate_name();
 
@@ -235,16 +651,137 @@ fn test_route() -> Result<Response> {
 }
 
 
-fn unauthenticated() -> Result<Response> {
+/// Keeps host and client names to MAX_NAME_LENGTH, they end up in every message about them
+fn check_name(key: &str, name: &str) -> ApiResult<()> {
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(ApiError::InvalidParameter(format!("{key} can be at most {MAX_NAME_LENGTH} characters")));
+    }
+
+    Ok(())
+}
+
+/// How long a poll waits for messages: what it asked for with wait, up to max_poll_wait_seconds
+fn poll_wait(config: &Config, query: &HashMap<String, String>) -> ApiResult<i64> {
+    Ok(optional_query_parsed::<u64>(query, "wait")?
+        .map_or(config.poll_timeout_seconds, |wait| wait.min(config.max_poll_wait_seconds as u64) as i64))
+}
+
+/// A page of mailbox messages, the cursor to poll from next, and a hint at when to do that
+fn messages_body(
+    store: &dyn SignalingStore,
+    mailbox: &Mailbox,
+    messages: Vec<MailboxMessage>,
+    since: Option<&str>,
+) -> ApiResult<Value> {
+    let cursor = messages.last().map(|message| message.id.clone()).or(since.map(String::from));
+
+    // Only a full page could have left anything behind. Everything before since has been
+    // acknowledged by now, so whatever's in the mailbox past this page is what's left
+    let has_more = messages.len() >= store.config().message_batch_size
+        && store.mailbox_length(mailbox)? > messages.len();
+    let next_poll_ms = if has_more {
+        0
+    } else if !messages.is_empty() || store.is_session_active(mailbox.session_name())? {
+        ACTIVE_POLL_MS
+    } else {
+        IDLE_POLL_MS
+    };
+
+    let messages: Vec<Value> = messages.into_iter().map(|message| json!({
+        "id": message.id,
+        "message": message.message,
+    })).collect();
+
+    Ok(json!({
+        "success": true,
+        "messages": messages,
+        "cursor": cursor,
+        "has_more": has_more,
+        "next_poll_ms": next_poll_ms,
+        "server_time": store.now(),
+    }))
+}
+
+/// How full a session is, as shown to hosts and in the session list
+fn occupancy_body(occupancy: Option<Occupancy>) -> Value {
+    match occupancy {
+        Some(occupancy) => json!({
+            "clients": occupancy.clients,
+            "waiting": occupancy.waiting,
+            "max_clients": occupancy.max_clients,
+        }),
+        None => Value::Null,
+    }
+}
+
+/// ICE candidates from a request body, as a candidates list and/or a single candidate, followed
+/// by the end of them if end_of_candidates is set. client_name is who they're from, None for the host
+fn candidate_messages(config: &Config, body: &Value, client_name: Option<&str>) -> ApiResult<Vec<SignalMessage>> {
+    let mut candidates = match &body["candidates"] {
+        Value::Null => Vec::new(),
+        candidates => candidates.as_array()
+            .and_then(|candidates|
+                candidates
+                    .iter()
+                    .map(|candidate| candidate.as_str().map(String::from))
+                    .collect::<Option<Vec<_>>>()
+            )
+            .ok_or_else(|| ApiError::InvalidParameter("Candidates must be an array of string candidates".into()))?,
+    };
+    if let Some(candidate) = optional_json_str(body, "candidate")? {
+        candidates.push(candidate.into());
+    }
+
+    // An empty candidate is how browsers say they're done, which goes in end_of_candidates here
+    if candidates.iter().any(String::is_empty) {
+        return Err(ApiError::InvalidParameter("Candidates can't be empty, send end_of_candidates instead".into()));
+    }
+
+    let mut messages = Vec::new();
+    if !candidates.is_empty() {
+        messages.push(SignalMessage::IceCandidate { client_name: client_name.map(String::from), candidates });
+    }
+    if optional_json_bool(body, "end_of_candidates")?.unwrap_or(false) {
+        messages.push(SignalMessage::EndOfCandidates {
+            client_name: client_name.map(String::from),
+            ufrag: optional_json_str(body, "ufrag")?.map(String::from),
+            mid: optional_json_str(body, "mid")?.map(String::from),
+        });
+    }
+    if messages.is_empty() {
+        return Err(ApiError::InvalidParameter("Send candidates, a candidate or end_of_candidates".into()));
+    }
+
+    for message in &messages {
+        message.check_limits(config)?;
+    }
+
+    Ok(messages)
+}
+
+/// HEAD on a mailbox only says whether the secret is good. Polling would renew the session and
+/// acknowledge messages, which a HEAD mustn't do, so it stops short of that
+fn head_response() -> ApiResult<Response> {
     http::Response::builder()
-        .status(401)
+        .status(200)
         .body(None)
-        .map_err(|_| anyhow!("Failed to build response"))
+        .map_err(ApiError::from)
 }
 
-fn get_json_body(req: &Request) -> Result<Value> {
-    // Retrieve variables
-    let body = req.body().as_ref().ok_or_else(|| anyhow!("Invalid body"))?;
-    let body = std::str::from_utf8(body).map_err(|_| anyhow!("Invalid body"))?;
-    serde_json::from_str(body).map_err(|_| anyhow!("Invalid body"))
+/// The request's JSON body, with any path parameters on top
+fn get_json_body(req: &Request, params: &Params) -> ApiResult<Value> {
+    // No body is the same as an empty one, everything might be in the path and the Authorization header
+    let body = match req.body().as_deref() {
+        None | Some([]) => "{}",
+        Some(body) => std::str::from_utf8(body).map_err(|_| ApiError::InvalidBody)?,
+    };
+    let mut body: Value = serde_json::from_str(body).map_err(|_| ApiError::InvalidBody)?;
+
+    if let Value::Object(body) = &mut body {
+        for (key, value) in params {
+            body.insert(key.clone(), Value::String(value.clone()));
+        }
+    }
+
+    Ok(body)
 }
\ No newline at end of file
@@ -0,0 +1,463 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::random_util::generate_secret;
+use crate::store::{
+    self, ClientStatus, Mailbox, MailboxMessage, PublicSession, SessionOptions, SignalingStore,
+};
+
+/// Something that goes away by itself at expires_at
+struct Expiring<T> {
+    value: T,
+    expires_at: i64,
+}
+
+struct SessionRecord {
+    public: bool,
+    host_name: String,
+    host_secret: String,
+    created_at: i64,
+    options: SessionOptions,
+    clients: BTreeMap<String, ClientStatus>,
+    /// Client name and offer, in line
+    waiting: VecDeque<(String, String)>,
+    /// Client name -> where they joined from
+    addresses: BTreeMap<String, String>,
+    bans: BTreeSet<String>,
+}
+
+#[derive(Default)]
+struct MailboxRecord {
+    /// Kept even when the mailbox is empty so ids never go backwards
+    next_id: u64,
+    messages: VecDeque<(u64, Value)>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    test_value: u32,
+    sessions: BTreeMap<String, Expiring<SessionRecord>>,
+    client_secrets: HashMap<String, Expiring<String>>,
+    mailboxes: HashMap<String, Expiring<MailboxRecord>>,
+    counters: HashMap<String, Expiring<i64>>,
+    /// Token id -> when its denial takes effect
+    denied_tokens: HashMap<String, Expiring<i64>>,
+}
+
+/// Signaling store that lives entirely in process memory.
+///
+/// Nothing is shared between instances and nothing survives the process, so this is only useful
+/// for running the handlers on the host, e.g. in tests. Reads never block since nobody else can
+/// be writing while we wait.
+#[derive(Default)]
+pub struct MemoryStore {
+    config: Config,
+    state: RefCell<MemoryState>,
+    /// Seconds added to the real clock, so tests can fast-forward past expiry
+    clock_offset: Cell<i64>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_config(config: &Config) -> Self {
+        Self {
+            config: config.clone(),
+            ..Self::default()
+        }
+    }
+
+    /// Moves this store's clock forward, expiring anything that would have expired by then
+    pub fn advance_clock(&self, seconds: i64) {
+        self.clock_offset.set(self.clock_offset.get() + seconds);
+    }
+
+    /// Drops everything that has expired
+    fn expire(&self) {
+        let now = self.now();
+        let mut state = self.state.borrow_mut();
+
+        state.sessions.retain(|_, session| session.expires_at > now);
+        state.client_secrets.retain(|_, secret| secret.expires_at > now);
+        state.mailboxes.retain(|_, mailbox| mailbox.expires_at > now);
+        state.counters.retain(|_, counter| counter.expires_at > now);
+        state.denied_tokens.retain(|_, denial| denial.expires_at > now);
+    }
+}
+
+impl SignalingStore for MemoryStore {
+    fn get_test_value(&self) -> Result<u32> {
+        Ok(self.state.borrow().test_value)
+    }
+
+    fn set_test_value(&self, val: u32) -> Result<()> {
+        self.state.borrow_mut().test_value = val;
+        Ok(())
+    }
+
+    fn has_session(&self, session_name: &str) -> Result<bool> {
+        self.expire();
+        Ok(self.state.borrow().sessions.contains_key(session_name))
+    }
+
+    fn register_session(
+        &self,
+        session_name: &str,
+        is_public: bool,
+        host_name: &str,
+        options: &SessionOptions,
+    ) -> Result<Option<String>> {
+        if self.has_session(session_name)? {
+            return Ok(None);
+        }
+
+        let host_secret = generate_secret(self.config.secret_length);
+        let created_at = self.now();
+
+        self.state.borrow_mut().sessions.insert(session_name.into(), Expiring {
+            value: SessionRecord {
+                public: is_public,
+                host_name: host_name.into(),
+                host_secret: host_secret.clone(),
+                created_at,
+                options: options.clone(),
+                clients: BTreeMap::new(),
+                waiting: VecDeque::new(),
+                addresses: BTreeMap::new(),
+                bans: BTreeSet::new(),
+            },
+            expires_at: created_at + self.config.session_ttl_seconds,
+        });
+
+        Ok(Some(host_secret))
+    }
+
+    fn get_session_options(&self, session_name: &str) -> Result<Option<SessionOptions>> {
+        self.expire();
+        let state = self.state.borrow();
+        Ok(state.sessions.get(session_name).map(|session| session.value.options.clone()))
+    }
+
+    fn renew_session(&self, session_name: &str) -> Result<Option<i64>> {
+        self.expire();
+        let expires_at = self.now() + self.config.session_ttl_seconds;
+        let mut state = self.state.borrow_mut();
+
+        let Some(session) = state.sessions.get_mut(session_name) else {
+            return Ok(None);
+        };
+        session.expires_at = expires_at;
+
+        let mut mailboxes = vec![Mailbox::Host { session_name }.key()];
+        let mut secrets = Vec::new();
+        for client_name in session.value.clients.keys() {
+            mailboxes.push(Mailbox::Client { session_name, client_name }.key());
+            mailboxes.push(Mailbox::HeldForHost { session_name, client_name }.key());
+            mailboxes.push(Mailbox::HeldForClient { session_name, client_name }.key());
+            secrets.push(store::client_secret_key(session_name, client_name));
+        }
+
+        for key in mailboxes {
+            if let Some(mailbox) = state.mailboxes.get_mut(&key) {
+                mailbox.expires_at = expires_at;
+            }
+        }
+        for key in secrets {
+            if let Some(secret) = state.client_secrets.get_mut(&key) {
+                secret.expires_at = expires_at;
+            }
+        }
+
+        Ok(Some(expires_at))
+    }
+
+    fn delete_session(&self, session_name: &str) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+
+        state.sessions.remove(session_name);
+        state.mailboxes.remove(&Mailbox::Host { session_name }.key());
+
+        Ok(())
+    }
+
+    fn get_host_secret(&self, session_name: &str) -> Result<Option<String>> {
+        self.expire();
+        let state = self.state.borrow();
+        Ok(state.sessions.get(session_name).map(|session| session.value.host_secret.clone()))
+    }
+
+    fn list_public_sessions(
+        &self,
+        prefix: Option<&str>,
+        min_age: Option<i64>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<PublicSession>, Option<String>)> {
+        self.expire();
+        let now = self.now();
+        let state = self.state.borrow();
+
+        let mut ret = Vec::new();
+
+        let sessions = state.sessions.iter()
+            .filter(|(_, session)| session.value.public)
+            .filter(|(name, _)| name.starts_with(prefix.unwrap_or_default()))
+            .filter(|(name, _)| cursor.is_none_or(|cursor| name.as_str() > cursor))
+            .filter(|(_, session)| now - session.value.created_at >= min_age.unwrap_or(0));
+
+        for (name, session) in sessions {
+            ret.push(PublicSession {
+                session_name: name.clone(),
+                host_name: session.value.host_name.clone(),
+                created_at: session.value.created_at,
+            });
+
+            if ret.len() >= limit {
+                return Ok((ret, Some(name.clone())));
+            }
+        }
+
+        Ok((ret, None))
+    }
+
+    fn register_client_secret(&self, session_name: &str, client_name: &str, status: ClientStatus) -> Result<String> {
+        let secret = generate_secret(self.config.secret_length);
+        let key = store::client_secret_key(session_name, client_name);
+        let mut state = self.state.borrow_mut();
+
+        state.client_secrets.insert(key, Expiring {
+            value: secret.clone(),
+            expires_at: self.now() + self.config.session_ttl_seconds,
+        });
+        if let Some(session) = state.sessions.get_mut(session_name) {
+            session.value.clients.insert(client_name.into(), status);
+        }
+
+        Ok(secret)
+    }
+
+    fn get_client_secret(&self, session_name: &str, client_name: &str) -> Result<Option<String>> {
+        self.expire();
+        let key = store::client_secret_key(session_name, client_name);
+        Ok(self.state.borrow().client_secrets.get(&key).map(|secret| secret.value.clone()))
+    }
+
+    fn get_clients(&self, session_name: &str) -> Result<Vec<(String, ClientStatus)>> {
+        self.expire();
+        let state = self.state.borrow();
+
+        Ok(state.sessions.get(session_name)
+            .map(|session| session.value.clients.iter().map(|(name, status)| (name.clone(), *status)).collect())
+            .unwrap_or_default())
+    }
+
+    fn get_client_status(&self, session_name: &str, client_name: &str) -> Result<Option<ClientStatus>> {
+        self.expire();
+        let state = self.state.borrow();
+
+        Ok(state.sessions.get(session_name).and_then(|session| session.value.clients.get(client_name).copied()))
+    }
+
+    fn set_client_status(&self, session_name: &str, client_name: &str, status: ClientStatus) -> Result<()> {
+        if let Some(session) = self.state.borrow_mut().sessions.get_mut(session_name) {
+            session.value.clients.insert(client_name.into(), status);
+        }
+
+        Ok(())
+    }
+
+    fn remove_client(&self, session_name: &str, client_name: &str) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+
+        state.client_secrets.remove(&store::client_secret_key(session_name, client_name));
+        state.mailboxes.remove(&Mailbox::Client { session_name, client_name }.key());
+        if let Some(session) = state.sessions.get_mut(session_name) {
+            session.value.clients.remove(client_name);
+        }
+
+        Ok(())
+    }
+
+    fn expire_client(&self, session_name: &str, client_name: &str, seconds: i64) -> Result<()> {
+        let expires_at = self.now() + seconds;
+        let mut state = self.state.borrow_mut();
+
+        if let Some(secret) = state.client_secrets.get_mut(&store::client_secret_key(session_name, client_name)) {
+            secret.expires_at = expires_at;
+        }
+        if let Some(mailbox) = state.mailboxes.get_mut(&Mailbox::Client { session_name, client_name }.key()) {
+            mailbox.expires_at = expires_at;
+        }
+        if let Some(session) = state.sessions.get_mut(session_name) {
+            session.value.clients.remove(client_name);
+        }
+
+        Ok(())
+    }
+
+    fn set_client_address(&self, session_name: &str, client_name: &str, address: &str) -> Result<()> {
+        if let Some(session) = self.state.borrow_mut().sessions.get_mut(session_name) {
+            session.value.addresses.insert(client_name.into(), address.into());
+        }
+
+        Ok(())
+    }
+
+    fn get_client_address(&self, session_name: &str, client_name: &str) -> Result<Option<String>> {
+        self.expire();
+        let state = self.state.borrow();
+        Ok(state.sessions.get(session_name).and_then(|session| session.value.addresses.get(client_name).cloned()))
+    }
+
+    fn add_ban(&self, session_name: &str, entry: &str) -> Result<()> {
+        if let Some(session) = self.state.borrow_mut().sessions.get_mut(session_name) {
+            session.value.bans.insert(entry.into());
+        }
+
+        Ok(())
+    }
+
+    fn is_banned(&self, session_name: &str, entry: &str) -> Result<bool> {
+        self.expire();
+        let state = self.state.borrow();
+        Ok(state.sessions.get(session_name).is_some_and(|session| session.value.bans.contains(entry)))
+    }
+
+    fn enqueue_waiting(&self, session_name: &str, client_name: &str, rtc_offer: &str) -> Result<usize> {
+        self.expire();
+        let mut state = self.state.borrow_mut();
+
+        let session = state.sessions.get_mut(session_name).ok_or(ApiError::NoSuchSession)?;
+        session.value.waiting.push_back((client_name.into(), rtc_offer.into()));
+
+        Ok(session.value.waiting.len())
+    }
+
+    fn dequeue_waiting(&self, session_name: &str) -> Result<Option<(String, String)>> {
+        self.expire();
+        let mut state = self.state.borrow_mut();
+
+        Ok(state.sessions.get_mut(session_name).and_then(|session| session.value.waiting.pop_front()))
+    }
+
+    fn remove_waiting(&self, session_name: &str, client_name: &str) -> Result<()> {
+        if let Some(session) = self.state.borrow_mut().sessions.get_mut(session_name) {
+            session.value.waiting.retain(|(name, _)| name != client_name);
+        }
+
+        Ok(())
+    }
+
+    fn increment_counter(&self, key: &str, window_seconds: i64) -> Result<i64> {
+        self.expire();
+        let expires_at = self.now() + window_seconds;
+        let mut state = self.state.borrow_mut();
+
+        let counter = state.counters.entry(key.into()).or_insert(Expiring { value: 0, expires_at });
+        counter.value += 1;
+
+        Ok(counter.value)
+    }
+
+    fn get_counter(&self, key: &str) -> Result<i64> {
+        self.expire();
+        Ok(self.state.borrow().counters.get(key).map_or(0, |counter| counter.value))
+    }
+
+    fn extend_counter(&self, key: &str, expires_at: i64) -> Result<()> {
+        self.expire();
+        if let Some(counter) = self.state.borrow_mut().counters.get_mut(key) {
+            counter.expires_at = counter.expires_at.max(expires_at);
+        }
+
+        Ok(())
+    }
+
+    fn deny_token(&self, token_id: &str, from: i64) -> Result<()> {
+        let expires_at = self.now() + self.config.token_ttl_seconds;
+        self.state.borrow_mut().denied_tokens.insert(token_id.into(), Expiring { value: from, expires_at });
+        Ok(())
+    }
+
+    fn get_token_denial(&self, token_id: &str) -> Result<Option<i64>> {
+        self.expire();
+        Ok(self.state.borrow().denied_tokens.get(token_id).map(|denial| denial.value))
+    }
+
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn now(&self) -> i64 {
+        store::now() + self.clock_offset.get()
+    }
+
+    fn push_message(&self, mailbox: &Mailbox, message: &Value) -> Result<()> {
+        self.expire();
+        let expires_at = self.now() + self.config.session_ttl_seconds;
+        let mut state = self.state.borrow_mut();
+
+        let mailbox = state.mailboxes.entry(mailbox.key()).or_insert_with(|| Expiring {
+            value: MailboxRecord::default(),
+            expires_at,
+        });
+        mailbox.value.next_id += 1;
+        let id = mailbox.value.next_id;
+        mailbox.value.messages.push_back((id, message.clone()));
+        mailbox.expires_at = expires_at;
+
+        Ok(())
+    }
+
+    fn mailbox_length(&self, mailbox: &Mailbox) -> Result<usize> {
+        self.expire();
+        Ok(self.state.borrow().mailboxes.get(&mailbox.key()).map_or(0, |mailbox| mailbox.value.messages.len()))
+    }
+
+    fn trim_mailbox(&self, mailbox: &Mailbox, keep: usize) -> Result<usize> {
+        let mut state = self.state.borrow_mut();
+        let Some(mailbox) = state.mailboxes.get_mut(&mailbox.key()) else {
+            return Ok(0);
+        };
+
+        let messages = &mut mailbox.value.messages;
+        let dropped = messages.len().saturating_sub(keep);
+        messages.drain(..dropped);
+        Ok(dropped)
+    }
+
+    /// Never waits, nothing else could send a message in the meantime
+    fn read_messages(&self, mailbox: &Mailbox, since: Option<&str>, _wait_seconds: i64) -> Result<Vec<MailboxMessage>> {
+        self.expire();
+        let since = since.map(store::parse_sequence_cursor).transpose()?.unwrap_or(0);
+        let state = self.state.borrow();
+
+        let Some(mailbox) = state.mailboxes.get(&mailbox.key()) else {
+            return Ok(Vec::new());
+        };
+
+        Ok(mailbox.value.messages.iter()
+            .filter(|(id, _)| *id > since)
+            .take(self.config.message_batch_size)
+            .map(|(id, message)| MailboxMessage { id: id.to_string(), message: message.clone() })
+            .collect())
+    }
+
+    fn ack_messages(&self, mailbox: &Mailbox, cursor: &str) -> Result<()> {
+        self.expire();
+        let cursor = store::parse_sequence_cursor(cursor)?;
+
+        if let Some(mailbox) = self.state.borrow_mut().mailboxes.get_mut(&mailbox.key()) {
+            mailbox.value.messages.retain(|(id, _)| *id > cursor);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::error::{ApiError, ApiResult};
+
+/// An RTC session description, as the browser hands it to us
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionDescription {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub sdp: String,
+}
+
+/// Everything that can end up in a mailbox, tagged by "type"
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SignalMessage {
+    /// To the host: a client wants in, with their offer
+    StartJoin { client_name: String, client_offer: String },
+    /// To a client: the host's answer to their offer
+    Answer { answer: SessionDescription },
+    /// ICE candidates, client_name says who they're from when they're going to the host
+    IceCandidate {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_name: Option<String>,
+        candidates: Vec<String>,
+    },
+    /// No more ICE candidates are coming, for the given ufrag and mid if there are any
+    EndOfCandidates {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_name: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ufrag: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mid: Option<String>,
+    },
+    /// Something went wrong on the other end
+    Error { message: String },
+    /// To a client: the host let them in
+    JoinAccepted,
+    /// To a client: the host turned them away
+    JoinRejected { reason: Option<String> },
+    /// To a client: the session is full and they're in line
+    Waiting { position: usize },
+    /// To a client: they're off the waiting list and on to the host
+    Promoted,
+    /// To a client: the host threw them out
+    Kicked { reason: Option<String> },
+    /// To a client: the host ended the session
+    SessionClosed,
+    /// To the host: a client left
+    ClientLeft { client_name: String },
+}
+
+impl SignalMessage {
+    /// Parses and validates a message, e.g. from a request body
+    pub fn parse(value: Value) -> ApiResult<Self> {
+        serde_json::from_value(value).map_err(|e| ApiError::InvalidMessage(format!("Invalid message: {e}")))
+    }
+
+    /// Can a host send this to a client themselves? Everything else only comes from us
+    pub fn is_from_host(&self) -> bool {
+        matches!(
+            self,
+            SignalMessage::Answer { .. }
+                | SignalMessage::IceCandidate { client_name: None, .. }
+                | SignalMessage::EndOfCandidates { client_name: None, .. }
+                | SignalMessage::Error { .. }
+        )
+    }
+
+    /// ICE candidates, or the end of them. These wait for the offer or answer they go with
+    pub fn is_candidate(&self) -> bool {
+        matches!(self, SignalMessage::IceCandidate { .. } | SignalMessage::EndOfCandidates { .. })
+    }
+
+    /// Checks any SDP and candidates, and the message as a whole, against the configured limits,
+    /// and that candidates at least look like candidates
+    pub fn check_limits(&self, config: &Config) -> ApiResult<()> {
+        match self {
+            SignalMessage::StartJoin { client_offer, .. } => check_sdp(config, client_offer)?,
+            SignalMessage::Answer { answer } => check_sdp(config, &answer.sdp)?,
+            SignalMessage::IceCandidate { candidates, .. } => check_candidates(config, candidates)?,
+            _ => {},
+        }
+
+        if self.to_value().to_string().len() > config.max_message_bytes {
+            return Err(ApiError::PayloadTooLarge(format!("Messages can be at most {} bytes", config.max_message_bytes)));
+        }
+
+        Ok(())
+    }
+
+    pub fn to_value(&self) -> Value {
+        // Nothing in here can fail to serialize
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+}
+
+/// Checks an offer or answer against max_sdp_bytes
+pub fn check_sdp(config: &Config, sdp: &str) -> ApiResult<()> {
+    if sdp.len() > config.max_sdp_bytes {
+        return Err(ApiError::PayloadTooLarge(format!("SDP can be at most {} bytes", config.max_sdp_bytes)));
+    }
+
+    Ok(())
+}
+
+/// Checks a batch of ICE candidates against max_candidates_per_message and max_candidate_bytes
+pub fn check_candidates(config: &Config, candidates: &[impl AsRef<str>]) -> ApiResult<()> {
+    if candidates.len() > config.max_candidates_per_message {
+        return Err(ApiError::PayloadTooLarge(format!(
+            "At most {} candidates can be sent at once",
+            config.max_candidates_per_message,
+        )));
+    }
+
+    if candidates.iter().any(|candidate| candidate.as_ref().len() > config.max_candidate_bytes) {
+        return Err(ApiError::PayloadTooLarge(format!("Candidates can be at most {} bytes", config.max_candidate_bytes)));
+    }
+
+    if !candidates.iter().all(|candidate| looks_like_candidate(candidate.as_ref())) {
+        return Err(ApiError::InvalidParameter(
+            "Candidates must be candidate: lines, or RTCIceCandidateInit JSON with one".into(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Is this an ICE candidate line (candidate:...), or an RTCIceCandidateInit as JSON whose candidate
+/// is one? Browsers hand out either, and the other side can pass both straight to addIceCandidate
+fn looks_like_candidate(candidate: &str) -> bool {
+    let is_line = |line: &str| line.strip_prefix("candidate:").is_some_and(|rest| !rest.trim().is_empty());
+
+    if candidate.starts_with('{') {
+        return serde_json::from_str::<Value>(candidate)
+            .is_ok_and(|init| init["candidate"].as_str().is_some_and(is_line));
+    }
+
+    is_line(candidate)
+}
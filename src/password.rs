@@ -0,0 +1,42 @@
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+
+use crate::random_util::generate_secret;
+use crate::token::constant_time_eq;
+
+/// PBKDF2 rounds for new hashes. Old hashes keep whatever they were made with
+const ROUNDS: u32 = 100_000;
+const SALT_LENGTH: usize = 16;
+
+/// Hashes a password for storage, as pbkdf2-sha256$rounds$salt$hash
+pub fn hash_password(password: &str) -> String {
+    let salt = generate_secret(SALT_LENGTH);
+    let hash = derive(password, &salt, ROUNDS);
+
+    format!("pbkdf2-sha256${ROUNDS}${salt}${hash}")
+}
+
+/// Checks a password against a hash from hash_password
+pub fn verify_password(password: &str, stored: &str) -> bool {
+    let mut parts = stored.split('$');
+
+    let (Some("pbkdf2-sha256"), Some(rounds), Some(salt), Some(hash), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return false;
+    };
+
+    let Ok(rounds) = rounds.parse::<u32>() else {
+        return false;
+    };
+
+    constant_time_eq(&derive(password, salt, rounds), hash)
+}
+
+/// Hex encoded PBKDF2-HMAC-SHA256
+fn derive(password: &str, salt: &str, rounds: u32) -> String {
+    let mut out = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt.as_bytes(), rounds, &mut out);
+
+    out.iter().map(|byte| format!("{byte:02x}")).collect()
+}
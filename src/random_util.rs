@@ -1,4 +1,4 @@
-use rand::seq::IteratorRandom;
+use rand::{distributions::Alphanumeric, seq::IteratorRandom, Rng};
 
 static NAME_PIECES: [&str; 3] = [
     include_str!("names/names_1.txt"),
@@ -10,4 +10,12 @@ static NAME_PIECES: [&str; 3] = [
 pub fn generate_name() -> String {
     let mut rng = rand::thread_rng();
     NAME_PIECES.map(|list| list.lines().choose(&mut rng).unwrap()).join(" ")
-}
\ No newline at end of file
+}
+/// Generates a secret token for e.g. authentication
+pub fn generate_secret(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
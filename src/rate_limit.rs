@@ -0,0 +1,125 @@
+use serde_json::Value;
+use spin_sdk::http::Response;
+
+use crate::error::{ApiError, ApiResult};
+use crate::req_helpers::{client_address, parse_query};
+use crate::router::Context;
+use crate::store::rate_limit_key;
+
+/// So many requests per window for each subject, e.g. each address
+struct Budget {
+    name: &'static str,
+    /// Who's being counted. None if the request doesn't say, which skips the budget
+    subject: Option<String>,
+    /// 0 for no limit
+    limit: i64,
+}
+
+/// Starting sessions, by address
+pub fn limit_session_creation(ctx: &Context, next: &dyn Fn() -> ApiResult<Response>) -> ApiResult<Response> {
+    check(ctx, &[
+        Budget { name: "sessions_by_ip", subject: address(ctx), limit: ctx.config.rate_limit_sessions_per_ip },
+    ])?;
+
+    next()
+}
+
+/// Joining sessions, by address and by the session being joined, so one host's queue can't be flooded
+pub fn limit_joins(ctx: &Context, next: &dyn Fn() -> ApiResult<Response>) -> ApiResult<Response> {
+    check(ctx, &[
+        Budget { name: "joins_by_ip", subject: address(ctx), limit: ctx.config.rate_limit_joins_per_ip },
+        Budget { name: "joins_by_session", subject: field(ctx, "session_name"), limit: ctx.config.rate_limit_joins_per_session },
+    ])?;
+
+    next()
+}
+
+/// Everything else, polls included, by address and by the session (and client) it's about
+pub fn limit_requests(ctx: &Context, next: &dyn Fn() -> ApiResult<Response>) -> ApiResult<Response> {
+    let subject = field(ctx, "session_name").map(|session_name| match field(ctx, "client_name") {
+        Some(client_name) => format!("{session_name}/{client_name}"),
+        None => session_name,
+    });
+
+    check(ctx, &[
+        Budget { name: "requests_by_ip", subject: address(ctx), limit: ctx.config.rate_limit_requests_per_ip },
+        Budget { name: "requests_by_client", subject, limit: ctx.config.rate_limit_requests_per_client },
+    ])?;
+
+    next()
+}
+
+/// Counts the request against each budget, or turns it away if any of them are used up.
+///
+/// Counts are kept per fixed window, and estimated over the last window_seconds by weighting
+/// the previous window's count by how much of it is still in range. So a burst right at the end
+/// of one window doesn't get a whole new budget at the start of the next
+fn check(ctx: &Context, budgets: &[Budget]) -> ApiResult<()> {
+    let window_seconds = ctx.config.rate_limit_window_seconds;
+    let now = ctx.store.now();
+    let window = now / window_seconds;
+    let elapsed = now % window_seconds;
+
+    let mut keys = Vec::new();
+    for budget in budgets {
+        let Some(subject) = &budget.subject else {
+            continue;
+        };
+        if budget.limit == 0 {
+            continue;
+        }
+
+        let previous = ctx.store.get_counter(&rate_limit_key(budget.name, subject, window - 1))?;
+        let current = ctx.store.get_counter(&rate_limit_key(budget.name, subject, window))?;
+
+        // Scaled up by window_seconds, to stay in whole numbers
+        let excess = previous * (window_seconds - elapsed) + current * window_seconds - budget.limit * window_seconds;
+        if excess >= 0 {
+            let retry_after = retry_after(excess + 1, previous, current, window_seconds - elapsed);
+            return Err(ApiError::TooManyRequests { retry_after });
+        }
+
+        keys.push(rate_limit_key(budget.name, subject, window));
+    }
+
+    // Only counted once it's let through, so being turned away doesn't push the wait back
+    for key in keys {
+        ctx.store.increment_counter(&key, 2 * window_seconds)?;
+    }
+
+    Ok(())
+}
+
+/// Seconds until enough of the estimate has slid out of range. The previous window's share
+/// shrinks by previous a second until this window ends, then the current one's by current a second
+fn retry_after(excess: i64, previous: i64, current: i64, remaining: i64) -> i64 {
+    let seconds = if excess <= previous * remaining {
+        div_ceil(excess, previous)
+    } else {
+        remaining + div_ceil(excess - previous * remaining, current)
+    };
+
+    seconds.max(1)
+}
+
+fn div_ceil(a: i64, b: i64) -> i64 {
+    (a + b - 1) / b
+}
+
+fn address(ctx: &Context) -> Option<String> {
+    client_address(ctx.req, &ctx.config.client_ip_header)
+}
+
+/// A field from wherever the route takes it, path, query or JSON body
+fn field(ctx: &Context, name: &str) -> Option<String> {
+    if let Some(value) = ctx.params.get(name) {
+        return Some(value.clone());
+    }
+
+    if let Some(value) = parse_query(ctx.req).ok().and_then(|mut query| query.remove(name)) {
+        return Some(value);
+    }
+
+    let body: Value = serde_json::from_slice(ctx.req.body().as_ref()?).ok()?;
+    body[name].as_str().map(String::from)
+}
@@ -1,207 +1,547 @@
 #![allow(dead_code, unused)]
 
-use std::marker::PhantomData;
-
-use anyhow::{anyhow, Result};
-use rand::{distributions::Alphanumeric, Rng};
+use anyhow::{anyhow, Context, Result};
 use serde_json::{json, Value};
 use spin_sdk::{
     redis::{self, RedisParameter, RedisResult},
 };
 
-// TODO LATER Apparently Spin has an integrated SQL-like KV store, maybe use that.
-//            However, implicit data expiration is nice...
-
-const REDIS_ADDRESS_ENV: &str = "REDIS_ADDRESS";
+use crate::config::Config;
+use crate::error::{ApiError, StoreFailure};
+use crate::random_util::generate_secret;
+use crate::store::{
+    self, ClientStatus, Mailbox, MailboxMessage, PublicSession, SessionOptions, SignalingStore,
+};
 
-pub struct RedisHelper(PhantomData<()>);
+/// Sorted set (all scores 0) of public session names, so we can range over them lexicographically
+const PUBLIC_INDEX_KEY: &str = "sessions:public";
+/// Sorted set of public session names scored by when they expire, so we can prune the index
+const PUBLIC_EXPIRY_KEY: &str = "sessions:public:expiry";
+
+/// Reserves a session name and writes the whole session in one go, so names can't collide and
+/// we can't be left with half a session if we die partway through.
+/// KEYS: session hash, public index, public expiry index
+/// ARGV: session name, ttl, public, host name, host secret, created at, max clients (0 for no limit), waiting list,
+///   password hash ("" for none)
+/// Returns 1 if registered, 0 if the name was already taken
+const REGISTER_SESSION_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return 0
+end
+
+redis.call('HSET', KEYS[1], 'public', ARGV[3], 'host_name', ARGV[4], 'host_secret', ARGV[5], 'created_at', ARGV[6],
+    'max_clients', ARGV[7], 'waiting_list', ARGV[8], 'password_hash', ARGV[9])
+redis.call('EXPIRE', KEYS[1], ARGV[2])
+
+if ARGV[3] == '1' then
+    redis.call('ZADD', KEYS[2], 0, ARGV[1])
+    redis.call('ZADD', KEYS[3], tonumber(ARGV[6]) + tonumber(ARGV[2]), ARGV[1])
+end
+
+return 1
+"#;
+
+/// Moves the expiry of a session and everything hanging off of it, in one go so they can't drift apart.
+/// The client keys are built here from the client list, so they're passed as prefixes rather than KEYS
+/// KEYS: session hash, host mailbox, client list, public expiry index, waiting list, client addresses, ban list
+/// ARGV: session name, expires at, client secret key prefix, client mailbox key prefix, held for host key prefix,
+/// held for client key prefix
+/// Returns 1 if renewed, 0 if the session is already gone
+const RENEW_SESSION_SCRIPT: &str = r#"
+if redis.call('EXPIREAT', KEYS[1], ARGV[2]) == 0 then
+    return 0
+end
+
+redis.call('EXPIREAT', KEYS[2], ARGV[2])
+redis.call('EXPIREAT', KEYS[3], ARGV[2])
+redis.call('EXPIREAT', KEYS[5], ARGV[2])
+redis.call('EXPIREAT', KEYS[6], ARGV[2])
+redis.call('EXPIREAT', KEYS[7], ARGV[2])
+
+for _, client in ipairs(redis.call('HKEYS', KEYS[3])) do
+    redis.call('EXPIREAT', ARGV[3] .. client, ARGV[2])
+    redis.call('EXPIREAT', ARGV[4] .. client, ARGV[2])
+    redis.call('EXPIREAT', ARGV[5] .. client, ARGV[2])
+    redis.call('EXPIREAT', ARGV[6] .. client, ARGV[2])
+end
+
+if redis.call('HGET', KEYS[1], 'public') == '1' then
+    redis.call('ZADD', KEYS[4], 'XX', ARGV[2], ARGV[1])
+end
+
+return 1
+"#;
+
+/// Claims a client name in a session, checking it's free and that there's room in the same step as registering
+/// them, so two joins can't both get the same name or the last place.
+/// KEYS: session hash, client secret, client list
+/// ARGV: client name, client secret, ttl
+/// Returns the status they were registered with, 'taken' if someone has the name, 'full' if there's no room and
+/// no waiting list, or 'gone' if there's no such session
+const CLAIM_CLIENT_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[2]) == 1 then
+    return 'taken'
+end
+
+local options = redis.call('HMGET', KEYS[1], 'max_clients', 'waiting_list')
+if not options[1] then
+    return 'gone'
+end
+
+local status = 'pending'
+local max_clients = tonumber(options[1])
+if max_clients > 0 then
+    local clients = 0
+    for _, client_status in ipairs(redis.call('HVALS', KEYS[3])) do
+        if client_status ~= 'waiting' then
+            clients = clients + 1
+        end
+    end
+
+    if clients >= max_clients then
+        if options[2] ~= '1' then
+            return 'full'
+        end
+        status = 'waiting'
+    end
+end
+
+redis.call('SET', KEYS[2], ARGV[2], 'EX', ARGV[3])
+redis.call('HSET', KEYS[3], ARGV[1], status)
+redis.call('EXPIRE', KEYS[3], ARGV[3])
+return status
+"#;
+
+/// Signaling store backed by an external Redis server.
+/// A session's client list is a hash of client name -> status
+pub struct RedisHelper {
+    address: String,
+    config: Config,
+}
 
 // General/test stuff
 impl RedisHelper {
-    fn address() -> Result<String> {
-        // Unfortunately there doesn't seem to be a way to include this with env variables without it ending up in VC?
-        //std::env::var(REDIS_ADDRESS_ENV).map_err(|_| anyhow!("Failed to get redis connection"))
+    pub fn open(config: &Config) -> Result<Self> {
+        let address = config.redis_address.clone()
+            .ok_or_else(|| anyhow!("No redis_address configured"))?;
 
-        // Just embedding for now
-        Ok(String::from(include_str!("../redis.env")))
-    }
-
-    /// Retrieve our test value from Redis, to test connectivity
-    pub fn get_test_value() -> Result<u32> {
-        match redis::get(&Self::address()?, "test") {
-            Ok(value) => {
-                if value.is_empty() { Ok(0u32) }
-                else { Ok(std::str::from_utf8(value.as_slice())?.parse::<u32>()?) }
-            },
-            _ => {
-                println!("New sequence");
-                Ok(0u32)
-            }
-        }
-    }
-
-    /// Update our test value in Redis, to test connectivity
-    pub fn set_test_value(val: u32) -> Result<()> {
-        redis::set(&Self::address()?, "test", val.to_string().as_bytes()).map_err(|_| {
-            anyhow!("Failed to update value")
-        })
+        Ok(Self { address, config: config.clone() })
     }
 
     /// Wrapper for redis::execute
-    fn execute(command: &str, arguments: &[RedisParameter]) -> Result<Vec<RedisResult>> {
+    fn execute(&self, command: &str, arguments: &[RedisParameter]) -> Result<Vec<RedisResult>> {
         // TODO wrap RedisParameter so we can just pass in String like a sane person instead of encoding it everywhere
-        redis::execute(&Self::address()?, command, arguments)
-            .map_err(|_| anyhow!("Command failed: {command}"))
+        redis::execute(&self.address, command, arguments)
+            .map_err(|e| StoreFailure::error(format!("Command failed: {command}: {e:?}")))
     }
 
-    /// Generates a secret token for e.g. authentication
-    fn generate_secret() -> String {
-        rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(16)
-            .map(char::from)
-            .collect()
+    /// Decodes a list of bulk string results, e.g. from ZRANGE
+    fn decode_strings(res: &[RedisResult]) -> Result<Vec<String>> {
+        res.iter().map(|r| match r {
+            RedisResult::Binary(val) => std::str::from_utf8(val)
+                .map(String::from)
+                .context("Invalid string result"),
+            _ => Err(anyhow!("Unexpected result type")),
+        }).collect()
     }
 }
 
 // Session management
 impl RedisHelper {
-    pub fn has_session(session_name: &str) -> Result<bool> {
-        let key = RedisParameter::Binary("sessions".as_bytes());
-        let name = RedisParameter::Binary(session_name.as_bytes());
-        let res = Self::execute("HEXISTS", &[key, name]).map_err(|_| anyhow!("Error retrieving session"))?;
+    fn set_session_expire(&self, session_name: &str, seconds: i64) -> Result<()> {
+        let key = store::session_key(session_name);
+        let key = RedisParameter::Binary(key.as_bytes());
+        let seconds = RedisParameter::Int64(seconds);
 
-        let res = res.first().ok_or_else(|| anyhow!("Error retrieving session"))?;
+        self.execute("EXPIRE", &[key, seconds]).context("Failed to set session expiration")?;
 
-        match res {
-            RedisResult::Int64(1) => Ok(true),
-            RedisResult::Int64(0) => Ok(false),
-            _ => Err(anyhow!("Error retrieving session")),
+        Ok(())
+    }
+
+    /// Removes sessions from the public index
+    fn unindex_public_sessions(&self, session_names: &[String]) -> Result<()> {
+        if session_names.is_empty() {
+            return Ok(());
+        }
+
+        for key in [PUBLIC_INDEX_KEY, PUBLIC_EXPIRY_KEY] {
+            let mut args = vec![RedisParameter::Binary(key.as_bytes())];
+            args.extend(session_names.iter().map(|name| RedisParameter::Binary(name.as_bytes())));
+
+            self.execute("ZREM", &args).context("Failed to prune session index")?;
         }
+
+        Ok(())
     }
 
-    /// Registers a new session and returns the host's authentication secret
-    pub fn register_session(session_name: &str, is_public: bool, host_name: &str) -> Result<String> {
-        // TODO remove they key if we fail anywhere here?
-        let host_secret = Self::generate_secret();
-        
-        Self::set_session_property(session_name, "public", RedisParameter::Int64(is_public as i64))?;
-        Self::set_session_property(session_name, "host_name", RedisParameter::Binary(host_name.as_bytes()))?;
-        Self::set_session_property(session_name, "host_secret", RedisParameter::Binary(host_secret.as_bytes()))?;
-        Self::set_session_expire(session_name, 600)?;
-        
-        Ok(host_secret)
+    /// Drops every session whose expiry has passed from the public index
+    fn prune_public_sessions(&self) -> Result<()> {
+        let now = store::now().to_string();
+        let res = self.execute("ZRANGEBYSCORE", &[
+            RedisParameter::Binary(PUBLIC_EXPIRY_KEY.as_bytes()),
+            RedisParameter::Binary("-inf".as_bytes()),
+            RedisParameter::Binary(now.as_bytes()),
+        ]).context("Error retrieving expired sessions")?;
+
+        self.unindex_public_sessions(&Self::decode_strings(&res)?)
     }
 
-    fn set_session_property(session_name: &str, field: &str, value: RedisParameter) -> Result<()> {
-        let key = format!("sessions:{session_name}");
+    /// Retrieves the listing details for a session, or None if it no longer exists
+    fn get_public_session(&self, session_name: &str) -> Result<Option<PublicSession>> {
+        let key = store::session_key(session_name);
         let key = RedisParameter::Binary(key.as_bytes());
-        let field = RedisParameter::Binary(field.as_bytes());
+        let host_name = RedisParameter::Binary("host_name".as_bytes());
+        let created_at = RedisParameter::Binary("created_at".as_bytes());
+
+        let res = self.execute("HMGET", &[key, host_name, created_at])
+            .context("Error retrieving session")?;
+
+        match (res.first(), res.get(1)) {
+            (Some(RedisResult::Binary(host_name)), Some(RedisResult::Binary(created_at))) => {
+                let host_name = std::str::from_utf8(host_name).context("Error decoding host name")?;
+                let created_at = std::str::from_utf8(created_at)?.parse::<i64>()?;
+
+                Ok(Some(PublicSession {
+                    session_name: session_name.into(),
+                    host_name: host_name.into(),
+                    created_at,
+                }))
+            },
+            _ => Ok(None),
+        }
+    }
+}
 
-        Self::execute("HSET", &[key, field, value]).map_err(|_| anyhow!("Failed to set session property"))?;
+// Mailboxes, kept as streams
+impl RedisHelper {
+    /// Parses a stream entry id, e.g. 1678000000000-0
+    fn parse_stream_id(id: &str) -> Result<(u64, u64)> {
+        let invalid = || ApiError::InvalidParameter("Invalid cursor".into());
+        let (ms, seq) = id.split_once('-').ok_or_else(invalid)?;
+        let ms = ms.parse::<u64>().map_err(|_| invalid())?;
+        let seq = seq.parse::<u64>().map_err(|_| invalid())?;
+
+        Ok((ms, seq))
+    }
 
-        Ok(())
+    /// Decodes stream entries. Spin flattens nested replies, so each entry is three results:
+    /// id, "message", payload
+    fn decode_stream_entries(res: &[RedisResult]) -> Result<Vec<MailboxMessage>> {
+        res.chunks(3).map(|entry| match entry {
+            [RedisResult::Binary(id), _, RedisResult::Binary(message)] => Ok(MailboxMessage {
+                id: std::str::from_utf8(id).context("Invalid message id")?.into(),
+                message: serde_json::from_slice(message).context("Invalid message format")?,
+            }),
+            _ => Err(anyhow!("Unexpected message format")),
+        }).collect()
+    }
+}
+
+impl SignalingStore for RedisHelper {
+    /// Retrieve our test value from Redis, to test connectivity
+    fn get_test_value(&self) -> Result<u32> {
+        match redis::get(&self.address, "test") {
+            Ok(value) => {
+                if value.is_empty() { Ok(0u32) }
+                else { Ok(std::str::from_utf8(value.as_slice())?.parse::<u32>()?) }
+            },
+            // Not set yet, so start a new sequence
+            _ => Ok(0u32),
+        }
+    }
+
+    /// Update our test value in Redis, to test connectivity
+    fn set_test_value(&self, val: u32) -> Result<()> {
+        redis::set(&self.address, "test", val.to_string().as_bytes())
+            .map_err(|e| StoreFailure::error(format!("Failed to update value: {e:?}")))
     }
 
-    fn set_session_expire(session_name: &str, seconds: i64) -> Result<()> {
-        let key = format!("sessions:{session_name}");
+    fn has_session(&self, session_name: &str) -> Result<bool> {
+        let key = store::session_key(session_name);
         let key = RedisParameter::Binary(key.as_bytes());
-        let seconds = RedisParameter::Int64(seconds);
+        let res = self.execute("EXISTS", &[key]).context("Error retrieving session")?;
 
-        Self::execute("EXPIRE", &[key, seconds]).map_err(|_| anyhow!("Failed to set session expiration"))?;
+        let res = res.first().ok_or_else(|| anyhow!("Error retrieving session"))?;
 
-        Ok(())
+        match res {
+            RedisResult::Int64(1) => Ok(true),
+            RedisResult::Int64(0) => Ok(false),
+            _ => Err(anyhow!("Error retrieving session")),
+        }
     }
 
-    /// Determine if the given secret is correct for the host of session_name
-    pub fn authenticate_host_message(session_name: &str, host_secret: &str) -> Result<bool> {
-        let actual_secret = Self::get_host_secret(session_name)?;
+    fn register_session(
+        &self,
+        session_name: &str,
+        is_public: bool,
+        host_name: &str,
+        options: &SessionOptions,
+    ) -> Result<Option<String>> {
+        let host_secret = generate_secret(self.config.secret_length);
+        let key = store::session_key(session_name);
+
+        let res = self.execute("EVAL", &[
+            RedisParameter::Binary(REGISTER_SESSION_SCRIPT.as_bytes()),
+            RedisParameter::Int64(3),
+            RedisParameter::Binary(key.as_bytes()),
+            RedisParameter::Binary(PUBLIC_INDEX_KEY.as_bytes()),
+            RedisParameter::Binary(PUBLIC_EXPIRY_KEY.as_bytes()),
+            RedisParameter::Binary(session_name.as_bytes()),
+            RedisParameter::Int64(self.config.session_ttl_seconds),
+            RedisParameter::Int64(is_public as i64),
+            RedisParameter::Binary(host_name.as_bytes()),
+            RedisParameter::Binary(host_secret.as_bytes()),
+            RedisParameter::Int64(store::now()),
+            RedisParameter::Int64(options.max_clients.unwrap_or(0) as i64),
+            RedisParameter::Int64(options.waiting_list as i64),
+            RedisParameter::Binary(options.password_hash.as_deref().unwrap_or_default().as_bytes()),
+        ]).context("Failed to register session")?;
 
-        // Valid if we have a secret for this client and it matches the supplied value
-        match actual_secret {
-            Some(actual_secret) => {
-                if host_secret != actual_secret {
-                    println!("(Mismatch) Auth host got {host_secret} expecting {actual_secret}");
-                }
+        match res.first() {
+            Some(RedisResult::Int64(1)) => Ok(Some(host_secret)),
+            Some(RedisResult::Int64(0)) => Ok(None),
+            _ => Err(anyhow!("Failed to register session")),
+        }
+    }
 
-                Ok(actual_secret == host_secret)
-            },
-            None => {
-                println!("No host secret, got {host_secret}");
-                Ok(false)
+    fn get_session_options(&self, session_name: &str) -> Result<Option<SessionOptions>> {
+        let key = store::session_key(session_name);
+        let res = self.execute("HMGET", &[
+            RedisParameter::Binary(key.as_bytes()),
+            RedisParameter::Binary("max_clients".as_bytes()),
+            RedisParameter::Binary("waiting_list".as_bytes()),
+            RedisParameter::Binary("password_hash".as_bytes()),
+        ]).context("Error retrieving session")?;
+
+        match Self::decode_strings(&res).as_deref() {
+            Ok([max_clients, waiting_list, password_hash]) => {
+                let max_clients = max_clients.parse::<usize>().context("Error decoding session")?;
+
+                Ok(Some(SessionOptions {
+                    max_clients: Some(max_clients).filter(|max_clients| *max_clients > 0),
+                    waiting_list: waiting_list == "1",
+                    password_hash: Some(password_hash.clone()).filter(|password_hash| !password_hash.is_empty()),
+                }))
             },
+            _ => Ok(None),
+        }
+    }
+
+    fn renew_session(&self, session_name: &str) -> Result<Option<i64>> {
+        let expires_at = store::now() + self.config.session_ttl_seconds;
+        let key = store::session_key(session_name);
+        let host_mailbox = Mailbox::Host { session_name }.key();
+        let client_list = store::client_list_key(session_name);
+        let waiting_list = store::waiting_list_key(session_name);
+        let addresses = store::client_address_key(session_name);
+        let bans = store::ban_list_key(session_name);
+        // The keys for a client named "", i.e. everything up to the client's name
+        let client_secret_prefix = store::client_secret_key(session_name, "");
+        let client_mailbox_prefix = Mailbox::Client { session_name, client_name: "" }.key();
+        let held_for_host_prefix = Mailbox::HeldForHost { session_name, client_name: "" }.key();
+        let held_for_client_prefix = Mailbox::HeldForClient { session_name, client_name: "" }.key();
+
+        let res = self.execute("EVAL", &[
+            RedisParameter::Binary(RENEW_SESSION_SCRIPT.as_bytes()),
+            RedisParameter::Int64(7),
+            RedisParameter::Binary(key.as_bytes()),
+            RedisParameter::Binary(host_mailbox.as_bytes()),
+            RedisParameter::Binary(client_list.as_bytes()),
+            RedisParameter::Binary(PUBLIC_EXPIRY_KEY.as_bytes()),
+            RedisParameter::Binary(waiting_list.as_bytes()),
+            RedisParameter::Binary(addresses.as_bytes()),
+            RedisParameter::Binary(bans.as_bytes()),
+            RedisParameter::Binary(session_name.as_bytes()),
+            RedisParameter::Int64(expires_at),
+            RedisParameter::Binary(client_secret_prefix.as_bytes()),
+            RedisParameter::Binary(client_mailbox_prefix.as_bytes()),
+            RedisParameter::Binary(held_for_host_prefix.as_bytes()),
+            RedisParameter::Binary(held_for_client_prefix.as_bytes()),
+        ]).context("Failed to renew session")?;
+
+        match res.first() {
+            Some(RedisResult::Int64(1)) => Ok(Some(expires_at)),
+            Some(RedisResult::Int64(0)) => Ok(None),
+            _ => Err(anyhow!("Failed to renew session")),
         }
     }
 
-    /// Gets the host's secret for a session
-    fn get_host_secret(session_name: &str) -> Result<Option<String>> {
-        let key = format!("sessions:{session_name}");
-        println!("{key} host_secret");
+    fn delete_session(&self, session_name: &str) -> Result<()> {
+        self.unindex_public_sessions(&[session_name.into()])?;
+
+        let key = store::session_key(session_name);
+        let host_mailbox = Mailbox::Host { session_name }.key();
+        let client_list = store::client_list_key(session_name);
+        let waiting_list = store::waiting_list_key(session_name);
+        let addresses = store::client_address_key(session_name);
+        let bans = store::ban_list_key(session_name);
+
+        self.execute("DEL", &[
+            RedisParameter::Binary(key.as_bytes()),
+            RedisParameter::Binary(host_mailbox.as_bytes()),
+            RedisParameter::Binary(client_list.as_bytes()),
+            RedisParameter::Binary(waiting_list.as_bytes()),
+            RedisParameter::Binary(addresses.as_bytes()),
+            RedisParameter::Binary(bans.as_bytes()),
+        ]).context("Failed to delete session")?;
+
+        Ok(())
+    }
+
+    fn get_host_secret(&self, session_name: &str) -> Result<Option<String>> {
+        let key = store::session_key(session_name);
         let key = RedisParameter::Binary(key.as_bytes());
 
         let field = RedisParameter::Binary("host_secret".as_bytes());
 
-        let res = Self::execute("HGET", &[key, field])
-            .map_err(|_| anyhow!("Error retrieving host secret 1"))?;
+        let res = self.execute("HGET", &[key, field])
+            .context("Error retrieving host secret")?;
 
         if res.is_empty() {
-            println!("Empty res get host secret {}", res.len());
             return Ok(None);
         }
 
-        let secret = res.first().ok_or_else(|| anyhow!("Error retrieving host secret 2"))?;
+        let secret = res.first().ok_or_else(|| anyhow!("Error retrieving host secret"))?;
 
         match secret {
             RedisResult::Binary(val) => {
-                let decoded = std::str::from_utf8(val).map_err(|_| anyhow!("Error decoding host secret 3"))?;
+                let decoded = std::str::from_utf8(val).context("Error decoding host secret")?;
                 Ok(Some(decoded.into()))
             },
             RedisResult::Nil => Ok(None),
-            _ => Err(anyhow!("Error decoding host secret 4")),
+            _ => Err(anyhow!("Error decoding host secret")),
         }
     }
-}
 
-// Connection request management
-impl RedisHelper {
-    /// Determines if the given secret is correct for this client_name joining session_name
-    pub fn authenticate_client_message(session_name: &str, client_name: &str, client_secret: &str) -> Result<bool> {
-        let actual_secret = Self::get_client_secret(session_name, client_name)?;
-
-        // Valid if we have a secret for this client and it matches the supplied value
-        match actual_secret {
-            Some(actual_secret) => Ok(actual_secret == client_secret),
-            None => Ok(false),
+    fn list_public_sessions(
+        &self,
+        prefix: Option<&str>,
+        min_age: Option<i64>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<PublicSession>, Option<String>)> {
+        self.prune_public_sessions()?;
+
+        let now = store::now();
+
+        // Lexicographic bounds, see ZRANGEBYLEX. 0xFF sorts after anything in a UTF-8 name
+        let upper = match prefix {
+            Some(prefix) => [b"[", prefix.as_bytes(), &[0xFF]].concat(),
+            None => b"+".to_vec(),
+        };
+        let mut lower = match (cursor, prefix) {
+            (Some(cursor), Some(prefix)) if cursor < prefix => format!("[{prefix}"),
+            (Some(cursor), _) => format!("({cursor}"),
+            (None, Some(prefix)) => format!("[{prefix}"),
+            (None, None) => String::from("-"),
+        };
+
+        let mut ret = Vec::new();
+        let mut expired = Vec::new();
+
+        loop {
+            let res = self.execute("ZRANGEBYLEX", &[
+                RedisParameter::Binary(PUBLIC_INDEX_KEY.as_bytes()),
+                RedisParameter::Binary(lower.as_bytes()),
+                RedisParameter::Binary(&upper),
+                RedisParameter::Binary("LIMIT".as_bytes()),
+                RedisParameter::Int64(0),
+                RedisParameter::Int64(limit as i64),
+            ]).context("Error listing sessions")?;
+
+            let names = Self::decode_strings(&res)?;
+            let exhausted = names.len() < limit;
+
+            for name in names {
+                lower = format!("({name}");
+
+                let Some(session) = self.get_public_session(&name)? else {
+                    // The session hash expired before the index caught up
+                    expired.push(name);
+                    continue;
+                };
+
+                if now - session.created_at >= min_age.unwrap_or(0) {
+                    ret.push(session);
+                }
+
+                if ret.len() >= limit {
+                    self.unindex_public_sessions(&expired)?;
+                    return Ok((ret, Some(name)));
+                }
+            }
+
+            if exhausted {
+                self.unindex_public_sessions(&expired)?;
+                return Ok((ret, None));
+            }
         }
     }
 
-    /// Generates a secret for a client to join a session with
-    fn register_client_secret(session_name: &str, client_name: &str) -> Result<String> {
-        let secret = Self::generate_secret();
-        
+    fn register_client_secret(&self, session_name: &str, client_name: &str, status: ClientStatus) -> Result<String> {
+        let secret = generate_secret(self.config.secret_length);
+
         // Save to store
-        let key = format!("sessions:{session_name}:clients:{client_name}");
+        let key = store::client_secret_key(session_name, client_name);
         let key = RedisParameter::Binary(key.as_bytes());
 
         // Expire after a while
         let secret_parameter = RedisParameter::Binary(secret.as_bytes());
         let ex = RedisParameter::Binary("EX".as_bytes());
-        let expire_seconds = RedisParameter::Int64(600);
-
-        let res = Self::execute("SET", &[key, secret_parameter, ex, expire_seconds]);
+        let expire_seconds = RedisParameter::Int64(self.config.session_ttl_seconds);
+
+        self.execute("SET", &[key, secret_parameter, ex, expire_seconds.clone()])
+            .context("Failed to register client secret")?;
+
+        // And remember them, so they can be renewed along with the session
+        let client_list = store::client_list_key(session_name);
+        let client_list = RedisParameter::Binary(client_list.as_bytes());
+        self.execute("HSET", &[
+            client_list.clone(),
+            RedisParameter::Binary(client_name.as_bytes()),
+            RedisParameter::Binary(status.as_str().as_bytes()),
+        ])
+            .context("Failed to register client")?;
+        self.execute("EXPIRE", &[client_list, expire_seconds])
+            .context("Failed to register client")?;
 
         Ok(secret)
     }
 
-    /// Retrieves the secret for the specified client
-    fn get_client_secret(session_name: &str, client_name: &str) -> Result<Option<String>> {
-        let key = format!("sessions:{session_name}:clients:{client_name}");
+    fn claim_client(&self, session_name: &str, client_name: &str) -> Result<Option<(String, ClientStatus)>> {
+        let secret = generate_secret(self.config.secret_length);
+        let key = store::session_key(session_name);
+        let secret_key = store::client_secret_key(session_name, client_name);
+        let client_list = store::client_list_key(session_name);
+
+        let res = self.execute("EVAL", &[
+            RedisParameter::Binary(CLAIM_CLIENT_SCRIPT.as_bytes()),
+            RedisParameter::Int64(3),
+            RedisParameter::Binary(key.as_bytes()),
+            RedisParameter::Binary(secret_key.as_bytes()),
+            RedisParameter::Binary(client_list.as_bytes()),
+            RedisParameter::Binary(client_name.as_bytes()),
+            RedisParameter::Binary(secret.as_bytes()),
+            RedisParameter::Int64(self.config.session_ttl_seconds),
+        ]).context("Failed to register client")?;
+
+        match Self::decode_strings(&res)?.first().map(String::as_str) {
+            Some("taken") => Err(ApiError::NameTaken.into()),
+            Some("gone") => Err(ApiError::NoSuchSession.into()),
+            Some("full") => Ok(None),
+            Some(status) => Ok(Some((secret, ClientStatus::parse(status)?))),
+            None => Err(anyhow!("Failed to register client")),
+        }
+    }
+
+    fn get_client_secret(&self, session_name: &str, client_name: &str) -> Result<Option<String>> {
+        let key = store::client_secret_key(session_name, client_name);
         let key = RedisParameter::Binary(key.as_bytes());
-        let res = Self::execute("GET", &[key]).map_err(|_| anyhow!("Error retrieving client secret"))?;
+        let res = self.execute("GET", &[key]).context("Error retrieving client secret")?;
 
         match res.first() {
             Some(RedisResult::Binary(val)) => {
-                let decoded = std::str::from_utf8(val).map_err(|_| anyhow!("Error decoding client secret"))?;
+                let decoded = std::str::from_utf8(val).context("Error decoding client secret")?;
                 Ok(Some(decoded.into()))
             },
             // One of these is correct...
@@ -211,145 +551,362 @@ impl RedisHelper {
         }
     }
 
-    /// Does some session already have this client?
-    pub fn session_has_client(session_name: &str, client_name: &str) -> Result<bool> {
-        // Present if we have a secret registered for them
-        Ok(Self::get_client_secret(session_name, client_name)?.is_some())
+    fn get_clients(&self, session_name: &str) -> Result<Vec<(String, ClientStatus)>> {
+        let key = store::client_list_key(session_name);
+        let res = self.execute("HGETALL", &[RedisParameter::Binary(key.as_bytes())])
+            .context("Error retrieving clients")?;
+
+        // Flattened into name, status, name, status...
+        Self::decode_strings(&res)?
+            .chunks(2)
+            .map(|pair| match pair {
+                [name, status] => Ok((name.clone(), ClientStatus::parse(status)?)),
+                _ => Err(anyhow!("Error decoding clients")),
+            })
+            .collect()
     }
 
-    /// Initiates a client joining a session, returns their secret
-    pub fn initiate_join(session_name: &str, client_name: &str, rtc_offer: &str) -> Result<String> {
-        if Self::session_has_client(session_name, client_name)? {
-            return Err(anyhow!("Name already taken"));
+    fn get_client_status(&self, session_name: &str, client_name: &str) -> Result<Option<ClientStatus>> {
+        let key = store::client_list_key(session_name);
+        let res = self.execute("HGET", &[
+            RedisParameter::Binary(key.as_bytes()),
+            RedisParameter::Binary(client_name.as_bytes()),
+        ]).context("Error retrieving client status")?;
+
+        match res.first() {
+            Some(RedisResult::Binary(status)) => {
+                let status = std::str::from_utf8(status).context("Error decoding client status")?;
+                Ok(Some(ClientStatus::parse(status)?))
+            },
+            Some(RedisResult::Nil) | None => Ok(None),
+            _ => Err(anyhow!("Error decoding client status")),
         }
+    }
 
-        // Forward to the session host
-        Self::push_message_to_host(session_name, json!({
-            "type": "start_join",
-            "client_name": client_name,
-            "client_offer": rtc_offer
-        }))?;
+    fn set_client_status(&self, session_name: &str, client_name: &str, status: ClientStatus) -> Result<()> {
+        let key = store::client_list_key(session_name);
+        self.execute("HSET", &[
+            RedisParameter::Binary(key.as_bytes()),
+            RedisParameter::Binary(client_name.as_bytes()),
+            RedisParameter::Binary(status.as_str().as_bytes()),
+        ]).context("Failed to update client status")?;
 
-        Self::register_client_secret(session_name, client_name)
+        Ok(())
     }
 
-    /// Send one or more ice candidates from a client to a host
-    /// Assumes we are already authenticated
-    pub fn client_ice_candidate(session_name: &str, client_name: &str, candidates: Vec<&str>) -> Result<()> {
-        Self::push_message_to_host(session_name, json!({
-            "type": "ice_candidate",
-            "client_name": client_name,
-            "candidates": candidates
-        }))
+    fn remove_client(&self, session_name: &str, client_name: &str) -> Result<()> {
+        let secret_key = store::client_secret_key(session_name, client_name);
+        let mailbox = Mailbox::Client { session_name, client_name }.key();
+        let client_list = store::client_list_key(session_name);
+
+        self.execute("DEL", &[
+            RedisParameter::Binary(secret_key.as_bytes()),
+            RedisParameter::Binary(mailbox.as_bytes()),
+        ]).context("Failed to remove client")?;
+        self.execute("HDEL", &[
+            RedisParameter::Binary(client_list.as_bytes()),
+            RedisParameter::Binary(client_name.as_bytes()),
+        ]).context("Failed to remove client")?;
+
+        Ok(())
     }
 
-    /// Send one or more ice candidates from a host to a client
-    /// Assumes we are already authenticated
-    pub fn host_ice_candidate(session_name: &str, client_name: &str, candidates: Vec<String>) -> Result<()> {
-        Self::push_message_to_client(session_name, client_name, &json!({
-            "type": "ice_candidate",
-            "candidates": candidates
-        }))
+    fn expire_client(&self, session_name: &str, client_name: &str, seconds: i64) -> Result<()> {
+        let secret_key = store::client_secret_key(session_name, client_name);
+        let mailbox = Mailbox::Client { session_name, client_name }.key();
+
+        let client_list = store::client_list_key(session_name);
+
+        for key in [secret_key, mailbox] {
+            self.execute("EXPIRE", &[RedisParameter::Binary(key.as_bytes()), RedisParameter::Int64(seconds)])
+                .context("Failed to expire client")?;
+        }
+        self.execute("HDEL", &[
+            RedisParameter::Binary(client_list.as_bytes()),
+            RedisParameter::Binary(client_name.as_bytes()),
+        ]).context("Failed to expire client")?;
+
+        Ok(())
     }
 
-    /// Adds a message to the host's message queue/mailbox
-    fn push_message_to_host(session_name: &str, message: Value) -> Result<()> {
-        let message = message.to_string();
-        
-        let key = format!("sessions:{session_name}:message_queue");
+    fn set_client_address(&self, session_name: &str, client_name: &str, address: &str) -> Result<()> {
+        let key = store::client_address_key(session_name);
         let key = RedisParameter::Binary(key.as_bytes());
-        let message = RedisParameter::Binary(message.as_bytes());
 
-        Self::execute("LPUSH", &[key.clone(), message]).map_err(|e| anyhow!("Failed to enqueue message"))?;
-        Self::execute("EXPIRE", &[key.clone(), RedisParameter::Int64(600)]);
+        self.execute("HSET", &[
+            key.clone(),
+            RedisParameter::Binary(client_name.as_bytes()),
+            RedisParameter::Binary(address.as_bytes()),
+        ]).context("Failed to save client address")?;
+        self.execute("EXPIRE", &[key, RedisParameter::Int64(self.config.session_ttl_seconds)])
+            .context("Failed to save client address")?;
 
         Ok(())
     }
 
-    pub fn get_messages_for_host(session_name: &str) -> Result<Vec<String>> {
-        let key = format!("sessions:{session_name}:message_queue");
-        Self::read_message_queue(key)
+    fn get_client_address(&self, session_name: &str, client_name: &str) -> Result<Option<String>> {
+        let key = store::client_address_key(session_name);
+        let res = self.execute("HGET", &[
+            RedisParameter::Binary(key.as_bytes()),
+            RedisParameter::Binary(client_name.as_bytes()),
+        ]).context("Error retrieving client address")?;
+
+        match res.first() {
+            Some(RedisResult::Binary(address)) => Ok(Some(std::str::from_utf8(address)?.into())),
+            Some(RedisResult::Nil) | None => Ok(None),
+            _ => Err(anyhow!("Error decoding client address")),
+        }
     }
 
-    /// Adds a message to the host's message queue/mailbox
-    pub fn push_message_to_client(session_name: &str, client_name: &str, message: &Value) -> Result<()> {
-        let message = message.to_string();
-        
-        let key = format!("sessions:{session_name}:message_queue:{client_name}");
+    fn add_ban(&self, session_name: &str, entry: &str) -> Result<()> {
+        let key = store::ban_list_key(session_name);
         let key = RedisParameter::Binary(key.as_bytes());
-        let message = RedisParameter::Binary(message.as_bytes());
 
-        Self::execute("LPUSH", &[key, message]).map_err(|e| anyhow!("Failed to enqueue message"))?;
+        self.execute("SADD", &[key.clone(), RedisParameter::Binary(entry.as_bytes())])
+            .context("Failed to ban client")?;
+        self.execute("EXPIRE", &[key, RedisParameter::Int64(self.config.session_ttl_seconds)])
+            .context("Failed to ban client")?;
 
         Ok(())
     }
 
-    
-    
-    pub fn get_messages_for_client(session_name: &str, client_name: &str) -> Result<Vec<String>> {
-        let key = format!("sessions:{session_name}:message_queue:{client_name}");
-        Self::read_message_queue(key)
+    fn is_banned(&self, session_name: &str, entry: &str) -> Result<bool> {
+        let key = store::ban_list_key(session_name);
+        let res = self.execute("SISMEMBER", &[
+            RedisParameter::Binary(key.as_bytes()),
+            RedisParameter::Binary(entry.as_bytes()),
+        ]).context("Error retrieving ban list")?;
+
+        match res.first() {
+            Some(RedisResult::Int64(banned)) => Ok(*banned == 1),
+            _ => Err(anyhow!("Error retrieving ban list")),
+        }
+    }
+
+    fn enqueue_waiting(&self, session_name: &str, client_name: &str, rtc_offer: &str) -> Result<usize> {
+        let key = store::waiting_list_key(session_name);
+        let entry = json!([client_name, rtc_offer]).to_string();
+
+        let res = self.execute("RPUSH", &[
+            RedisParameter::Binary(key.as_bytes()),
+            RedisParameter::Binary(entry.as_bytes()),
+        ]).context("Failed to join waiting list")?;
+        self.execute("EXPIRE", &[RedisParameter::Binary(key.as_bytes()), RedisParameter::Int64(self.config.session_ttl_seconds)])
+            .context("Failed to join waiting list")?;
+
+        // RPUSH hands back the new length, which is where we ended up
+        match res.first() {
+            Some(RedisResult::Int64(position)) => Ok(*position as usize),
+            _ => Err(anyhow!("Failed to join waiting list")),
+        }
     }
 
-    /// Takes a bunch of messages from the specified queue and returns them as Strings
-    fn read_message_queue_future(key: String) -> Result<Vec<String>> {
-        // TODO if we fail to decode the messages will still be removed from the queue. Which, is probably fine anyway
-        // Alas, BLMPOP is not supported on our current substrate (6.2), use the other one
+    fn dequeue_waiting(&self, session_name: &str) -> Result<Option<(String, String)>> {
+        let key = store::waiting_list_key(session_name);
+        let res = self.execute("LPOP", &[RedisParameter::Binary(key.as_bytes())])
+            .context("Failed to read waiting list")?;
 
-        let key = RedisParameter::Binary(key.as_bytes());
+        let entry: Value = match res.first() {
+            Some(RedisResult::Binary(entry)) => serde_json::from_slice(entry).context("Invalid waiting list entry")?,
+            Some(RedisResult::Nil) | None => return Ok(None),
+            _ => return Err(anyhow!("Failed to read waiting list")),
+        };
 
-        let count = RedisParameter::Binary("COUNT".as_bytes());
+        match (entry[0].as_str(), entry[1].as_str()) {
+            (Some(client_name), Some(rtc_offer)) => Ok(Some((client_name.into(), rtc_offer.into()))),
+            _ => Err(anyhow!("Invalid waiting list entry")),
+        }
+    }
 
-        let res = Self::execute("BLMPOP", &[
-            RedisParameter::Int64(5), // 5 second timeout
-            RedisParameter::Int64(1), // 1 key to look at
-            key,
-            count,
-            RedisParameter::Int64(10), // Get at most 10 messages
-        ])?;
+    fn remove_waiting(&self, session_name: &str, client_name: &str) -> Result<()> {
+        let key = store::waiting_list_key(session_name);
+        let res = self.execute("LRANGE", &[
+            RedisParameter::Binary(key.as_bytes()),
+            RedisParameter::Int64(0),
+            RedisParameter::Int64(-1),
+        ]).context("Failed to read waiting list")?;
+
+        // LREM needs the exact entry, offer and all
+        for entry in Self::decode_strings(&res)? {
+            let parsed: Value = serde_json::from_str(&entry).context("Invalid waiting list entry")?;
+            if parsed[0].as_str() != Some(client_name) {
+                continue;
+            }
 
-        if res[0] == RedisResult::Nil {
-            // No results
-            return Ok(Vec::new());
+            self.execute("LREM", &[
+                RedisParameter::Binary(key.as_bytes()),
+                RedisParameter::Int64(0),
+                RedisParameter::Binary(entry.as_bytes()),
+            ]).context("Failed to leave waiting list")?;
         }
 
-        let ret: Result<Vec<String>> = match (res[0]) {
-            // No results
-            RedisResult::Nil => Ok(Vec::new()),
-            // Some results
-            RedisResult::Binary(_) => res.iter().skip(1).map(|r| match r {
-                RedisResult::Binary(message) =>
-                    std::str::from_utf8(message)
-                        .map(String::from)
-                        .map_err(|_| anyhow!("Invalid message format")),
-                _ => Err(anyhow!("Invalid message format"))
-            }).collect(),
-            _ => Err(anyhow!("Invalid message response"))
+        Ok(())
+    }
+
+    fn increment_counter(&self, key: &str, window_seconds: i64) -> Result<i64> {
+        let res = self.execute("INCR", &[RedisParameter::Binary(key.as_bytes())])
+            .context("Failed to update counter")?;
+
+        let count = match res.first() {
+            Some(RedisResult::Int64(count)) => *count,
+            _ => return Err(anyhow!("Failed to update counter")),
         };
-        
-        ret
+
+        // Only the first one starts the window, otherwise it would never run out
+        if count == 1 {
+            self.execute("EXPIRE", &[RedisParameter::Binary(key.as_bytes()), RedisParameter::Int64(window_seconds)])
+                .context("Failed to update counter")?;
+        }
+
+        Ok(count)
+    }
+
+    fn get_counter(&self, key: &str) -> Result<i64> {
+        let res = self.execute("GET", &[RedisParameter::Binary(key.as_bytes())])
+            .context("Failed to read counter")?;
+
+        match res.first() {
+            Some(RedisResult::Binary(count)) => Ok(std::str::from_utf8(count)?.parse::<i64>()?),
+            Some(RedisResult::Nil) | None => Ok(0),
+            _ => Err(anyhow!("Failed to read counter")),
+        }
     }
 
-    fn read_message_queue(key: String) -> Result<Vec<String>> {
+    fn extend_counter(&self, key: &str, expires_at: i64) -> Result<()> {
+        // Does nothing if the counter is gone. Callers only ever move it later, and GT needs Redis 7
+        self.execute("EXPIREAT", &[RedisParameter::Binary(key.as_bytes()), RedisParameter::Int64(expires_at)])
+            .context("Failed to update counter")?;
+
+        Ok(())
+    }
+
+    fn deny_token(&self, token_id: &str, from: i64) -> Result<()> {
+        let key = store::denied_token_key(token_id);
+
+        // Only needs to outlive the token
+        self.execute("SET", &[
+            RedisParameter::Binary(key.as_bytes()),
+            RedisParameter::Int64(from),
+            RedisParameter::Binary("EX".as_bytes()),
+            RedisParameter::Int64(self.config.token_ttl_seconds),
+        ])
+            .context("Failed to revoke token")?;
+
+        Ok(())
+    }
+
+    fn get_token_denial(&self, token_id: &str) -> Result<Option<i64>> {
+        let key = store::denied_token_key(token_id);
+        let res = self.execute("GET", &[RedisParameter::Binary(key.as_bytes())])
+            .context("Failed to read token deny-list")?;
+
+        match res.first() {
+            Some(RedisResult::Binary(from)) => Ok(Some(std::str::from_utf8(from)?.parse::<i64>()?)),
+            Some(RedisResult::Nil) | None => Ok(None),
+            _ => Err(anyhow!("Failed to read token deny-list")),
+        }
+    }
+
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn push_message(&self, mailbox: &Mailbox, message: &Value) -> Result<()> {
+        let message = message.to_string();
+
+        let key = mailbox.key();
         let key = RedisParameter::Binary(key.as_bytes());
-        let timeout_seconds = RedisParameter::Int64(5);
-        let args = [key, timeout_seconds];
+        let message = RedisParameter::Binary(message.as_bytes());
 
-        
-        let mut at = Self::execute("BLPOP", &args[..])?;
-        let mut ret = Vec::new();
+        // Mailboxes are streams, so they stay in order and every message gets an id to ack by
+        self.execute("XADD", &[
+            key.clone(),
+            RedisParameter::Binary("*".as_bytes()),
+            RedisParameter::Binary("message".as_bytes()),
+            message,
+        ]).context("Failed to enqueue message")?;
+        self.execute("EXPIRE", &[key, RedisParameter::Int64(self.config.session_ttl_seconds)])
+            .context("Failed to enqueue message")?;
 
-        while let Some(RedisResult::Binary(_)) = at.first() {
-            match at.last() {
-                Some(RedisResult::Binary(item)) => {
-                    ret.push(std::str::from_utf8(item)?.into());
-                },
-                _ => return Err(anyhow!("Unexpected message format")),
-            }
+        Ok(())
+    }
 
-            at = Self::execute("LPOP", &args[0..1])?
+    fn mailbox_length(&self, mailbox: &Mailbox) -> Result<usize> {
+        let key = mailbox.key();
+        let res = self.execute("XLEN", &[RedisParameter::Binary(key.as_bytes())])
+            .context("Failed to read mailbox")?;
+
+        match res.first() {
+            Some(RedisResult::Int64(length)) => Ok(*length as usize),
+            _ => Err(anyhow!("Failed to read mailbox")),
+        }
+    }
+
+    fn trim_mailbox(&self, mailbox: &Mailbox, keep: usize) -> Result<usize> {
+        // Trimming rather than deleting keeps the stream's last id, so new ids stay after old cursors
+        let key = mailbox.key();
+        let res = self.execute("XTRIM", &[
+            RedisParameter::Binary(key.as_bytes()),
+            RedisParameter::Binary("MAXLEN".as_bytes()),
+            RedisParameter::Int64(keep as i64),
+        ]).context("Failed to trim mailbox")?;
+
+        // XTRIM answers with how many entries it took off
+        match res.first() {
+            Some(RedisResult::Int64(dropped)) => Ok(*dropped as usize),
+            _ => Err(anyhow!("Failed to trim mailbox")),
+        }
+    }
+
+    fn read_messages(&self, mailbox: &Mailbox, since: Option<&str>, wait_seconds: i64) -> Result<Vec<MailboxMessage>> {
+        // 0 is before anything in the stream
+        let since = match since {
+            Some(since) => {
+                Self::parse_stream_id(since)?;
+                since
+            },
+            None => "0",
         };
 
-        Ok(ret)
+        let key = mailbox.key();
+        let mut params = vec![
+            RedisParameter::Binary("COUNT".as_bytes()),
+            RedisParameter::Int64(self.config.message_batch_size as i64),
+        ];
+
+        // BLOCK 0 would wait forever, so leave it off to not wait at all
+        if wait_seconds > 0 {
+            params.push(RedisParameter::Binary("BLOCK".as_bytes()));
+            params.push(RedisParameter::Int64(wait_seconds * 1000));
+        }
+
+        params.extend([
+            RedisParameter::Binary("STREAMS".as_bytes()),
+            RedisParameter::Binary(key.as_bytes()),
+            RedisParameter::Binary(since.as_bytes()),
+        ]);
+
+        let res = self.execute("XREAD", &params).context("Failed to read messages")?;
+
+        // Nothing at all if we timed out, otherwise the stream's key followed by its entries
+        match res.split_first() {
+            Some((_key, entries)) => Self::decode_stream_entries(entries),
+            None => Ok(Vec::new()),
+        }
     }
-}
\ No newline at end of file
+
+    fn ack_messages(&self, mailbox: &Mailbox, cursor: &str) -> Result<()> {
+        // MINID keeps everything at or after the id, so trim to the one just after the cursor
+        let (ms, seq) = Self::parse_stream_id(cursor)?;
+        let min_id = format!("{ms}-{}", seq + 1);
+
+        let key = mailbox.key();
+        self.execute("XTRIM", &[
+            RedisParameter::Binary(key.as_bytes()),
+            RedisParameter::Binary("MINID".as_bytes()),
+            RedisParameter::Binary(min_id.as_bytes()),
+        ]).context("Failed to acknowledge messages")?;
+
+        Ok(())
+    }
+}
@@ -1,56 +1,114 @@
 #![allow(unused)]
 use std::collections::HashMap;
-use anyhow::{anyhow,Result};
+use std::net::{IpAddr, SocketAddr};
 use serde_json::Value;
 use spin_sdk::http::Request;
 use urlencoding::decode;
 
+use crate::error::{ApiError, ApiResult};
+
 /// Takes some key/value pair from a query string and url-decods both sides
-fn decode_query_pair(key_value: (&str, &str)) -> Result<(String, String)> {
+fn decode_query_pair(key_value: (&str, &str)) -> ApiResult<(String, String)> {
     let (key, value) = key_value;
-    let key = decode(key)?.into_owned();
-    let value = decode(value)?.into_owned();
+    let key = decode(key).map_err(|_| ApiError::InvalidParameter("invalid query".into()))?.into_owned();
+    let value = decode(value).map_err(|_| ApiError::InvalidParameter(format!("invalid parameter {key}")))?.into_owned();
     Ok((key, value))
 }
 
 /// Returns a HashMap over the query/search parameters on this requrest
-pub fn parse_query(req: &Request) -> Result<HashMap<String, String>> {
-    let query = req.uri().query()
-        .ok_or_else(|| anyhow!("No query"))?;
+pub fn parse_query(req: &Request) -> ApiResult<HashMap<String, String>> {
+    // No query is the same as an empty one, required_query will complain about anything missing
+    let query = req.uri().query().unwrap_or_default();
     
     querystring::querify(query)
         .into_iter()
         .map(decode_query_pair)
-        .collect::<Result<HashMap<String, String>>>()
+        .collect::<ApiResult<HashMap<String, String>>>()
+
+}
 
+/// Like parse_query, with any path parameters on top
+pub fn parse_query_with_params(req: &Request, params: &HashMap<String, String>) -> ApiResult<HashMap<String, String>> {
+    let mut query = parse_query(req)?;
+    query.extend(params.clone());
+    Ok(query)
 }
 
 /// Returns the value of the specified key in the query, or an Err if not present
-pub fn required_query<'a>(query: &'a HashMap<String, String>, key: &str) -> Result<&'a String> {
+pub fn required_query<'a>(query: &'a HashMap<String, String>, key: &str) -> ApiResult<&'a String> {
     let value = query
         .get(key)
-        .ok_or_else(|| anyhow!(format!("missing required parameter {key}")))?;
+        .ok_or_else(|| ApiError::MissingParameter(key.into()))?;
     
     Ok(value)
 }
 
+/// Returns the value of the specified key in the query, parsed, or None if not present
+pub fn optional_query_parsed<T: std::str::FromStr>(query: &HashMap<String, String>, key: &str) -> ApiResult<Option<T>> {
+    query
+        .get(key)
+        .map(|value| value.parse::<T>().map_err(|_| ApiError::InvalidParameter(format!("invalid parameter {key}"))))
+        .transpose()
+}
+
 /// Returns the specified string value from a json object, or an Err
-pub fn required_json_str<'a>(value: &'a Value, key: &str) -> Result<&'a str> {
+pub fn required_json_str<'a>(value: &'a Value, key: &str) -> ApiResult<&'a str> {
     value[key]
         .as_str()
-        .ok_or_else(|| anyhow!(format!("missing required parameter {key}")))
+        .ok_or_else(|| ApiError::MissingParameter(key.into()))
 }
 
 /// Returns the specified string value from a json object, or an Err
-pub fn required_json_i64(value: &Value, key: &str) -> Result<i64> {
+pub fn required_json_i64(value: &Value, key: &str) -> ApiResult<i64> {
     value[key]
         .as_i64()
-        .ok_or_else(|| anyhow!(format!("missing required parameter {key}")))
+        .ok_or_else(|| ApiError::MissingParameter(key.into()))
 }
 
 /// Returns the specified string value from a json object, or an Err
-pub fn required_json_bool(value: &Value, key: &str) -> Result<bool> {
+pub fn required_json_bool(value: &Value, key: &str) -> ApiResult<bool> {
     value[key]
         .as_bool()
-        .ok_or_else(|| anyhow!(format!("missing required parameter {key}")))
+        .ok_or_else(|| ApiError::MissingParameter(key.into()))
+}
+
+/// Returns the specified number from a json object, None if not present, or an Err if it isn't a number
+pub fn optional_json_u64(value: &Value, key: &str) -> ApiResult<Option<u64>> {
+    match &value[key] {
+        Value::Null => Ok(None),
+        value => value.as_u64().map(Some).ok_or_else(|| ApiError::InvalidParameter(format!("invalid parameter {key}"))),
+    }
+}
+
+/// Returns the specified bool from a json object, None if not present, or an Err if it isn't a bool
+pub fn optional_json_bool(value: &Value, key: &str) -> ApiResult<Option<bool>> {
+    match &value[key] {
+        Value::Null => Ok(None),
+        value => value.as_bool().map(Some).ok_or_else(|| ApiError::InvalidParameter(format!("invalid parameter {key}"))),
+    }
+}
+
+/// Returns the specified string from a json object, None if not present, or an Err if it isn't a string
+pub fn optional_json_str<'a>(value: &'a Value, key: &str) -> ApiResult<Option<&'a str>> {
+    match &value[key] {
+        Value::Null => Ok(None),
+        value => value.as_str().map(Some).ok_or_else(|| ApiError::InvalidParameter(format!("invalid parameter {key}"))),
+    }
+}
+
+/// Where this request came from (without the port), going by the header in client_ip_header.
+/// A list like X-Forwarded-For's starts with the original client
+pub fn client_address(req: &Request, header: &str) -> Option<String> {
+    let value = req.headers().get(header)?.to_str().ok()?;
+    let address = value.split(',').next()?.trim();
+
+    // ip, ip:port, or [ipv6]:port
+    if let Ok(ip) = address.parse::<IpAddr>() {
+        return Some(ip.to_string());
+    }
+    if let Ok(address) = address.parse::<SocketAddr>() {
+        return Some(address.ip().to_string());
+    }
+
+    Some(address.to_string()).filter(|address| !address.is_empty())
 }
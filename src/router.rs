@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use http::Method;
+use spin_sdk::http::{Request, Response};
+use urlencoding::decode;
+
+use crate::config::Config;
+use crate::error::{ApiError, ApiResult};
+use crate::store::SignalingStore;
+
+/// Values captured by {placeholders} in a route's path, url-decoded
+pub type Params = HashMap<String, String>;
+
+pub type Handler = fn(&dyn SignalingStore, &Request, &Params) -> ApiResult<Response>;
+
+/// What middleware gets to look at
+pub struct Context<'a> {
+    pub store: &'a dyn SignalingStore,
+    pub config: &'a Config,
+    pub req: &'a Request,
+    /// Empty if no route matched
+    pub params: &'a Params,
+}
+
+/// Runs around a handler, or around the whole router. Calls next to carry on, or answers
+/// (or fails) early without it
+pub type Middleware = fn(&Context, next: &dyn Fn() -> ApiResult<Response>) -> ApiResult<Response>;
+
+struct Route {
+    method: Method,
+    segments: Vec<&'static str>,
+    handler: Handler,
+    middleware: Vec<Middleware>,
+}
+
+impl Route {
+    /// The params if this route's path matches, None if it doesn't
+    fn capture(&self, path: &str) -> Option<Params> {
+        let segments: Vec<&str> = path.split('/').collect();
+        if segments.len() != self.segments.len() {
+            return None;
+        }
+
+        let mut params = Params::new();
+        for (pattern, segment) in self.segments.iter().zip(segments) {
+            match pattern.strip_prefix('{').and_then(|name| name.strip_suffix('}')) {
+                Some(name) if !segment.is_empty() => {
+                    params.insert(name.into(), decode(segment).ok()?.into_owned());
+                },
+                Some(_) => return None,
+                None if *pattern != segment => return None,
+                None => {},
+            }
+        }
+
+        Some(params)
+    }
+}
+
+/// Picks a handler by method and path. Paths that exist but not for the request's method get a
+/// 405 (or for OPTIONS, a 204) listing the methods that are allowed, and HEAD is answered by the
+/// GET route without its body
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    middleware: Vec<Middleware>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a route, e.g. (GET, "/sessions/{session_name}/messages")
+    pub fn route(mut self, method: Method, pattern: &'static str, handler: Handler) -> Self {
+        self.routes.push(Route {
+            method,
+            segments: pattern.split('/').collect(),
+            handler,
+            middleware: Vec::new(),
+        });
+        self
+    }
+
+    /// Adds middleware to the last route added. The first one added runs first
+    pub fn with(mut self, middleware: Middleware) -> Self {
+        if let Some(route) = self.routes.last_mut() {
+            route.middleware.push(middleware);
+        }
+        self
+    }
+
+    /// Adds middleware that runs for every request, matched or not
+    pub fn wrap(mut self, middleware: Middleware) -> Self {
+        self.middleware.push(middleware);
+        self
+    }
+
+    pub fn handle(&self, store: &dyn SignalingStore, config: &Config, req: &Request) -> ApiResult<Response> {
+        let is_head = req.method() == Method::HEAD;
+        let method = if is_head { &Method::GET } else { req.method() };
+
+        let mut allowed = Vec::new();
+        let mut matched = None;
+        for route in &self.routes {
+            let Some(params) = route.capture(req.uri().path()) else {
+                continue;
+            };
+
+            if !allowed.contains(&route.method) {
+                allowed.push(route.method.clone());
+            }
+            if matched.is_none() && route.method == method {
+                matched = Some((route, params));
+            }
+        }
+
+        let params = matched.as_ref().map(|(_, params)| params.clone()).unwrap_or_default();
+        let ctx = Context { store, config, req, params: &params };
+
+        let endpoint = || match &matched {
+            Some((route, params)) => run(&route.middleware, &ctx, &|| (route.handler)(store, req, params)),
+            None if allowed.is_empty() => Err(ApiError::NotFound),
+            None if req.method() == Method::OPTIONS => http::Response::builder()
+                .status(204)
+                .header("Allow", allow_header(&allowed))
+                .body(None)
+                .map_err(ApiError::from),
+            None => Err(ApiError::MethodNotAllowed { allow: allow_header(&allowed) }),
+        };
+
+        let res = run(&self.middleware, &ctx, &endpoint)?;
+
+        if is_head {
+            let (parts, _) = res.into_parts();
+            return Ok(http::Response::from_parts(parts, None));
+        }
+
+        Ok(res)
+    }
+}
+
+/// Runs the middleware in order, then the endpoint
+fn run(middleware: &[Middleware], ctx: &Context, endpoint: &dyn Fn() -> ApiResult<Response>) -> ApiResult<Response> {
+    match middleware.split_first() {
+        Some((first, rest)) => first(ctx, &|| run(rest, ctx, endpoint)),
+        None => endpoint(),
+    }
+}
+
+/// e.g. "GET, HEAD, POST, OPTIONS"
+fn allow_header(methods: &[Method]) -> String {
+    let mut allow: Vec<&str> = methods.iter().map(Method::as_str).collect();
+    if methods.contains(&Method::GET) {
+        allow.push("HEAD");
+    }
+    allow.push("OPTIONS");
+    allow.join(", ")
+}
@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use anyhow::Result;
+use http::header::ACCEPT;
+use spin_sdk::http::{Request, Response};
+
+use crate::config::Config;
+use crate::error::{ApiError, ApiResult};
+use crate::store::MailboxMessage;
+
+/// How soon EventSource should reconnect once a response ends, in milliseconds
+const RETRY_MS: u32 = 500;
+
+/// Did the caller ask for text/event-stream rather than JSON?
+pub fn wants_event_stream(req: &Request) -> bool {
+    req.headers()
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|accept| accept.to_str().ok())
+        .any(|accept| accept.split(',').any(|media_type| media_type.trim().starts_with("text/event-stream")))
+}
+
+/// Where to pick a stream up from: Last-Event-ID when EventSource reconnects, otherwise since
+pub fn resume_cursor<'a>(req: &'a Request, query: &'a HashMap<String, String>) -> Option<&'a str> {
+    req.headers()
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty())
+        .or(query.get("since").map(String::as_str))
+}
+
+/// Answers a mailbox poll as an event stream, one event per message with its id as the event id.
+///
+/// Spin hands the response over all at once when the handler returns, so rather than holding
+/// a stream open this keeps polling (with a keepalive comment after every empty poll) until
+/// something arrives or stream_timeout_seconds is up, then ends. EventSource reconnects straight
+/// away with Last-Event-ID, which acknowledges everything up to there like since does
+pub fn event_stream(
+    config: &Config,
+    since: Option<&str>,
+    read: impl Fn(Option<&str>) -> Result<Vec<MailboxMessage>>,
+) -> ApiResult<Response> {
+    let polls = (config.stream_timeout_seconds / config.poll_timeout_seconds).max(1);
+
+    let mut body = format!("retry: {RETRY_MS}\n\n");
+    for _ in 0..polls {
+        let messages = read(since)?;
+        if messages.is_empty() {
+            body.push_str(": keepalive\n\n");
+            continue;
+        }
+
+        for message in messages {
+            // Compact JSON never spans lines, so it fits in a single data field
+            let _ = write!(body, "id: {}\ndata: {}\n\n", message.id, message.message);
+        }
+        break;
+    }
+
+    http::Response::builder()
+        .status(200)
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(Some(body.into()))
+        .map_err(ApiError::from)
+}
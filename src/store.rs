@@ -0,0 +1,1001 @@
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+use crate::config::{Config, MailboxOverflow};
+use crate::error::ApiError;
+use crate::message::SignalMessage;
+use crate::password::verify_password;
+use crate::token::{self, constant_time_eq, Claims, Role};
+
+#[cfg(target_arch = "wasm32")]
+use crate::config::StoreBackend;
+#[cfg(target_arch = "wasm32")]
+use crate::kv_helper::KvHelper;
+#[cfg(target_arch = "wasm32")]
+use crate::redis_helper::RedisHelper;
+
+/// A public session as shown in the session list
+pub struct PublicSession {
+    pub session_name: String,
+    pub host_name: String,
+    pub created_at: i64,
+}
+
+/// Limits a host can put on their session
+#[derive(Clone, Debug, Default)]
+pub struct SessionOptions {
+    /// Most clients that can be in the session (or waiting on the host) at once, None for no limit
+    pub max_clients: Option<usize>,
+    /// Whether joins over max_clients wait in line instead of being turned away
+    pub waiting_list: bool,
+    /// Hash of the password clients need to join with, from password::hash_password
+    pub password_hash: Option<String>,
+}
+
+/// How full a session is
+pub struct Occupancy {
+    /// Clients in the session or waiting on the host, these count towards max_clients
+    pub clients: usize,
+    /// Clients on the waiting list
+    pub waiting: usize,
+    pub max_clients: Option<usize>,
+}
+
+impl Occupancy {
+    pub fn is_full(&self) -> bool {
+        self.max_clients.is_some_and(|max_clients| self.clients >= max_clients)
+    }
+}
+
+/// How a join request went
+pub enum JoinOutcome {
+    /// Sent on to the host, with the client's secret
+    Pending(String),
+    /// The session is full, so the client is in line to be sent on to the host. Position 1 is next
+    Waiting { client_secret: String, position: usize },
+    /// The session is full and doesn't keep a waiting list
+    Full,
+}
+
+/// Whether a join password checked out
+#[derive(Debug, PartialEq, Eq)]
+pub enum PasswordCheck {
+    /// Right password, or the session doesn't have one
+    Correct,
+    Wrong,
+    /// Too many wrong guesses lately, try again later
+    Throttled,
+}
+
+/// Wrong join passwords a session takes before it stops checking for a while
+pub const MAX_FAILED_JOINS: i64 = 5;
+/// How long wrong join passwords count against a session
+pub const FAILED_JOIN_WINDOW_SECONDS: i64 = 60;
+
+/// How long after the last message a session still counts as busy, see is_session_active
+pub const ACTIVITY_WINDOW_SECONDS: i64 = 30;
+
+/// How long clients of a closed session (or rejected or kicked clients) can still get in to read that it's over
+pub const CLOSED_SESSION_GRACE_SECONDS: i64 = 60;
+
+/// A message sitting in a mailbox, along with its place in line
+pub struct MailboxMessage {
+    /// Opaque, increasing id. Pass it back as a cursor to acknowledge everything up to here
+    pub id: String,
+    pub message: Value,
+}
+
+/// Where a client is in joining a session
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientStatus {
+    /// Asked to join, waiting on the host
+    Pending,
+    /// Let in by the host
+    Accepted,
+    /// Let in by the host, who has sent their answer
+    Answered,
+    /// On the waiting list for a full session, the host hasn't heard of them yet
+    Waiting,
+}
+
+impl ClientStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClientStatus::Pending => "pending",
+            ClientStatus::Accepted => "accepted",
+            ClientStatus::Answered => "answered",
+            ClientStatus::Waiting => "waiting",
+        }
+    }
+
+    pub fn parse(status: &str) -> Result<Self> {
+        match status {
+            "pending" => Ok(ClientStatus::Pending),
+            "accepted" => Ok(ClientStatus::Accepted),
+            "answered" => Ok(ClientStatus::Answered),
+            "waiting" => Ok(ClientStatus::Waiting),
+            _ => Err(anyhow!("Invalid client status")),
+        }
+    }
+}
+
+/// A message queue for one of the parties in a session
+pub enum Mailbox<'a> {
+    /// Messages from clients to the session host
+    Host { session_name: &'a str },
+    /// Messages from the session host to one client
+    Client { session_name: &'a str, client_name: &'a str },
+    /// ICE candidates from a client, held back until the host lets them in
+    HeldForHost { session_name: &'a str, client_name: &'a str },
+    /// ICE candidates from the host, held back until they've sent the client their answer
+    HeldForClient { session_name: &'a str, client_name: &'a str },
+}
+
+impl<'a> Mailbox<'a> {
+    /// The key this mailbox is stored under
+    pub fn key(&self) -> String {
+        match self {
+            Mailbox::Host { session_name } => format!("sessions:{session_name}:message_queue"),
+            Mailbox::Client { session_name, client_name } => format!("sessions:{session_name}:message_queue:{client_name}"),
+            Mailbox::HeldForHost { session_name, client_name } => format!("sessions:{session_name}:held_for_host:{client_name}"),
+            Mailbox::HeldForClient { session_name, client_name } => format!("sessions:{session_name}:held_for_client:{client_name}"),
+        }
+    }
+
+    pub fn session_name(&self) -> &'a str {
+        match self {
+            Mailbox::Host { session_name }
+            | Mailbox::Client { session_name, .. }
+            | Mailbox::HeldForHost { session_name, .. }
+            | Mailbox::HeldForClient { session_name, .. } => session_name,
+        }
+    }
+}
+
+/// The key a session's properties are stored under
+pub fn session_key(session_name: &str) -> String {
+    format!("sessions:{session_name}")
+}
+
+/// The key a client's secret is stored under
+pub fn client_secret_key(session_name: &str, client_name: &str) -> String {
+    format!("sessions:{session_name}:clients:{client_name}")
+}
+
+/// The key a session's clients and their statuses are stored under
+pub fn client_list_key(session_name: &str) -> String {
+    format!("sessions:{session_name}:clients")
+}
+
+/// The key a session's waiting list is stored under
+pub fn waiting_list_key(session_name: &str) -> String {
+    format!("sessions:{session_name}:waiting")
+}
+
+/// The key a session's client addresses are stored under, client name -> address
+pub fn client_address_key(session_name: &str) -> String {
+    format!("sessions:{session_name}:addresses")
+}
+
+/// The key a session's ban list is stored under, see ban_entry
+pub fn ban_list_key(session_name: &str) -> String {
+    format!("sessions:{session_name}:bans")
+}
+
+/// What goes on a ban list for a client name or a source address
+pub fn ban_entry(kind: &str, value: &str) -> String {
+    format!("{kind}:{value}")
+}
+
+/// The key recent wrong join passwords from an address are counted under, per session. Callers
+/// without a known address share the one counter
+pub fn failed_join_key(session_name: &str, address: Option<&str>) -> String {
+    format!("sessions:{session_name}:failed_joins:{}", address.unwrap_or_default())
+}
+
+/// The key a revoked token's id is kept under, until the token would have expired anyway
+pub fn denied_token_key(token_id: &str) -> String {
+    format!("tokens:denied:{token_id}")
+}
+
+/// The key registrations of a session name are counted under while tokens are on, so a name
+/// isn't reused while tokens for an old session under it could still be valid
+pub fn session_name_reservation_key(session_name: &str) -> String {
+    format!("session_names:{session_name}")
+}
+
+/// The key messages sent in a session are counted under, one per ACTIVITY_WINDOW_SECONDS
+pub fn session_activity_key(session_name: &str, window: i64) -> String {
+    format!("sessions:{session_name}:activity:{window}")
+}
+
+/// The key requests against a rate limit budget are counted under, one per window
+pub fn rate_limit_key(budget: &str, subject: &str, window: i64) -> String {
+    format!("rate_limits:{budget}:{subject}:{window}")
+}
+
+/// Parses a cursor for stores that number their messages 1, 2, 3...
+pub fn parse_sequence_cursor(cursor: &str) -> Result<u64> {
+    cursor.parse::<u64>().map_err(|_| ApiError::InvalidParameter("Invalid cursor".into()).into())
+}
+
+/// Current unix time, in seconds
+pub fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Opens whichever backing store this deployment is configured to use
+#[cfg(target_arch = "wasm32")]
+pub fn open(config: &Config) -> Result<Box<dyn SignalingStore>> {
+    match config.store {
+        StoreBackend::Redis => Ok(Box::new(RedisHelper::open(config)?)),
+        StoreBackend::KeyValue => Ok(Box::new(KvHelper::open(config)?)),
+    }
+}
+
+/// Everything the signaling server needs to keep track of: sessions, secrets and mailboxes.
+/// Backends only provide the storage, the join protocol itself lives in the provided methods
+pub trait SignalingStore {
+    /// Retrieve our test value, to test connectivity
+    fn get_test_value(&self) -> Result<u32>;
+
+    /// Update our test value, to test connectivity
+    fn set_test_value(&self, val: u32) -> Result<()>;
+
+    fn has_session(&self, session_name: &str) -> Result<bool>;
+
+    /// Reserves session_name and registers a new session under it.
+    /// Returns the host's authentication secret, or None if the name is already taken.
+    /// Redis and the in-memory store check and register in one step. The key-value store can't, so
+    /// two sessions registered under the same name at the same moment can both succeed, the later
+    /// one overwriting the earlier
+    fn register_session(
+        &self,
+        session_name: &str,
+        is_public: bool,
+        host_name: &str,
+        options: &SessionOptions,
+    ) -> Result<Option<String>>;
+
+    /// Gets the limits a session was registered with, or None if it doesn't exist
+    fn get_session_options(&self, session_name: &str) -> Result<Option<SessionOptions>>;
+
+    /// Pushes back the expiry of a session and everything hanging off of it: the host's mailbox
+    /// and every client's secret and mailbox. Returns the new expiry time, or None if the session
+    /// has already expired
+    fn renew_session(&self, session_name: &str) -> Result<Option<i64>>;
+
+    /// Removes a session's properties, public listing, host mailbox, client list, waiting list,
+    /// client addresses and ban list.
+    /// Clients' own secrets and mailboxes are left alone
+    fn delete_session(&self, session_name: &str) -> Result<()>;
+
+    /// Gets the host's secret for a session
+    fn get_host_secret(&self, session_name: &str) -> Result<Option<String>>;
+
+    /// Lists public sessions in name order, starting after cursor (exclusive).
+    /// Returns the page along with the cursor for the next page, if there might be one
+    fn list_public_sessions(
+        &self,
+        prefix: Option<&str>,
+        min_age: Option<i64>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<(Vec<PublicSession>, Option<String>)>;
+
+    /// Generates a secret for a client to join a session with, and adds them to the session's client list
+    fn register_client_secret(&self, session_name: &str, client_name: &str, status: ClientStatus) -> Result<String>;
+
+    /// Retrieves the secret for the specified client
+    fn get_client_secret(&self, session_name: &str, client_name: &str) -> Result<Option<String>>;
+
+    /// Every client on a session's client list, and where they are in joining
+    fn get_clients(&self, session_name: &str) -> Result<Vec<(String, ClientStatus)>>;
+
+    /// Where a client is in joining, or None if they aren't on the session's client list
+    fn get_client_status(&self, session_name: &str, client_name: &str) -> Result<Option<ClientStatus>>;
+
+    /// Updates a client's status on the session's client list
+    fn set_client_status(&self, session_name: &str, client_name: &str, status: ClientStatus) -> Result<()>;
+
+    /// Removes a client's secret and mailbox, and takes them off the session's client list
+    fn remove_client(&self, session_name: &str, client_name: &str) -> Result<()>;
+
+    /// Makes a client's secret and mailbox expire in seconds, and takes them off the session's client
+    /// list so renewing the session doesn't bring them back
+    fn expire_client(&self, session_name: &str, client_name: &str, seconds: i64) -> Result<()>;
+
+    /// Remembers where a client joined from, so they can be banned by address
+    fn set_client_address(&self, session_name: &str, client_name: &str, address: &str) -> Result<()>;
+
+    fn get_client_address(&self, session_name: &str, client_name: &str) -> Result<Option<String>>;
+
+    /// Adds an entry from ban_entry to a session's ban list
+    fn add_ban(&self, session_name: &str, entry: &str) -> Result<()>;
+
+    /// Is this entry from ban_entry on the session's ban list?
+    fn is_banned(&self, session_name: &str, entry: &str) -> Result<bool>;
+
+    /// Puts a client at the back of a session's waiting list, along with the offer to send the host
+    /// once it's their turn. Returns their position in line
+    fn enqueue_waiting(&self, session_name: &str, client_name: &str, rtc_offer: &str) -> Result<usize>;
+
+    /// Takes the client at the front of a session's waiting list, returning their name and offer
+    fn dequeue_waiting(&self, session_name: &str) -> Result<Option<(String, String)>>;
+
+    /// Takes a client off a session's waiting list, wherever they are in it
+    fn remove_waiting(&self, session_name: &str, client_name: &str) -> Result<()>;
+
+    /// Adds one to the counter under key, returning the new count. A new counter disappears
+    /// window_seconds after it was started
+    fn increment_counter(&self, key: &str, window_seconds: i64) -> Result<i64>;
+
+    /// Reads the counter under key, 0 if it hasn't been started or has expired
+    fn get_counter(&self, key: &str) -> Result<i64>;
+
+    /// Keeps the counter under key around until at least expires_at, if it hasn't already gone
+    fn extend_counter(&self, key: &str, expires_at: i64) -> Result<()>;
+
+    /// Puts a token id on the deny-list, taking effect at from. It stays there until any token
+    /// with that id would have expired anyway
+    fn deny_token(&self, token_id: &str, from: i64) -> Result<()>;
+
+    /// When a token id's denial takes effect, or None if it isn't on the deny-list
+    fn get_token_denial(&self, token_id: &str) -> Result<Option<i64>>;
+
+    /// The settings this store was opened with
+    fn config(&self) -> &Config;
+
+    /// Adds a message to the end of a mailbox
+    fn push_message(&self, mailbox: &Mailbox, message: &Value) -> Result<()>;
+
+    /// How many messages are in a mailbox, i.e. not acknowledged yet
+    fn mailbox_length(&self, mailbox: &Mailbox) -> Result<usize>;
+
+    /// Drops the oldest messages in a mailbox until at most keep are left, returning how many went.
+    /// Ids carry on from where they were, so old cursors still work
+    fn trim_mailbox(&self, mailbox: &Mailbox, keep: usize) -> Result<usize>;
+
+    /// Returns the messages after since (or everything, if None) in the order they were sent,
+    /// waiting up to wait_seconds for some to arrive. Messages stay in the mailbox until acknowledged
+    fn read_messages(&self, mailbox: &Mailbox, since: Option<&str>, wait_seconds: i64) -> Result<Vec<MailboxMessage>>;
+
+    /// Deletes every message up to and including cursor
+    fn ack_messages(&self, mailbox: &Mailbox, cursor: &str) -> Result<()>;
+
+    /// Current unix time, in seconds
+    fn now(&self) -> i64 {
+        now()
+    }
+
+    /// Registers a new session under session_name, returning what the host authenticates with,
+    /// or None if the name is taken
+    fn create_session(
+        &self,
+        session_name: &str,
+        is_public: bool,
+        host_name: &str,
+        options: &SessionOptions,
+    ) -> Result<Option<String>> {
+        // Old tokens can't be checked against the store, so the name has to stay retired for as
+        // long as any of them could still be valid
+        if self.config().token_key.is_some() {
+            let key = session_name_reservation_key(session_name);
+            if self.increment_counter(&key, self.config().token_ttl_seconds)? > 1 {
+                return Ok(None);
+            }
+        }
+
+        let host_secret = self.register_session(session_name, is_public, host_name, options)?;
+        Ok(host_secret.map(|host_secret| self.issue_secret(session_name, None, host_secret)))
+    }
+
+    /// What a host (client_name None) or client authenticates with: a signed token if there's a
+    /// token_key, otherwise the secret the store generated for them
+    fn issue_secret(&self, session_name: &str, client_name: Option<&str>, secret: String) -> String {
+        let Some(key) = &self.config().token_key else {
+            return secret;
+        };
+
+        token::issue(key, &Claims {
+            session_name: session_name.into(),
+            role: if client_name.is_some() { Role::Client } else { Role::Host },
+            client_name: client_name.map(String::from),
+            expires_at: self.now() + self.config().token_ttl_seconds,
+            id: secret,
+        })
+    }
+
+    /// A fresh token for a host (client_name None) or client to replace the token they just
+    /// authenticated with, so they aren't locked out of a session they're keeping alive. Only once
+    /// less than half of the old one's lifetime is left, so most polls don't touch the store for it.
+    /// None otherwise, without a token_key, or once the token has been revoked, so it can't be
+    /// renewed past the deny-list
+    fn renew_secret(&self, token: &str, session_name: &str, client_name: Option<&str>) -> Result<Option<String>> {
+        let Some(claims) = self.config().token_key.as_ref().and_then(|key| token::verify(key, token, self.now())) else {
+            return Ok(None);
+        };
+        if claims.expires_at - self.now() > self.config().token_ttl_seconds / 2 {
+            return Ok(None);
+        }
+        if self.get_token_denial(&claims.id)?.is_some() {
+            return Ok(None);
+        }
+
+        // The name stays retired for as long as the new token is good for
+        let key = session_name_reservation_key(session_name);
+        self.extend_counter(&key, self.now() + self.config().token_ttl_seconds)?;
+
+        Ok(Some(self.issue_secret(session_name, client_name, claims.id)))
+    }
+
+    /// Checks a signed token is for this session and host (client_name None) or client, and
+    /// hasn't been revoked. The deny-list is the only thing looked up, on every request including
+    /// read-only polls, since skipping it would let a revoked token keep reading until it expires
+    fn verify_token(&self, token: &str, session_name: &str, client_name: Option<&str>) -> Result<bool> {
+        let Some(claims) = self.config().token_key.as_ref().and_then(|key| token::verify(key, token, self.now())) else {
+            return Ok(false);
+        };
+
+        let role = if client_name.is_some() { Role::Client } else { Role::Host };
+        if claims.session_name != session_name || claims.role != role || claims.client_name.as_deref() != client_name {
+            return Ok(false);
+        }
+
+        Ok(self.get_token_denial(&claims.id)?.is_none_or(|from| self.now() < from))
+    }
+
+    /// Revokes a client's token once seconds have passed, if they have one
+    fn revoke_client_token(&self, session_name: &str, client_name: &str, seconds: i64) -> Result<()> {
+        if self.config().token_key.is_none() {
+            return Ok(());
+        }
+
+        match self.get_client_secret(session_name, client_name)? {
+            Some(token_id) => self.deny_token(&token_id, self.now() + seconds),
+            None => Ok(()),
+        }
+    }
+
+    /// Determine if the given secret is correct for the host of session_name
+    fn authenticate_host_message(&self, session_name: &str, host_secret: &str) -> Result<bool> {
+        if self.config().token_key.is_some() {
+            return self.verify_token(host_secret, session_name, None);
+        }
+
+        let actual_secret = self.get_host_secret(session_name)?;
+
+        // Valid if we have a secret for this session and it matches the supplied value
+        match actual_secret {
+            Some(actual_secret) => Ok(constant_time_eq(&actual_secret, host_secret)),
+            None => Ok(false),
+        }
+    }
+
+    /// Determines if the given secret is correct for this client_name joining session_name
+    fn authenticate_client_message(&self, session_name: &str, client_name: &str, client_secret: &str) -> Result<bool> {
+        if self.config().token_key.is_some() {
+            return self.verify_token(client_secret, session_name, Some(client_name));
+        }
+
+        let actual_secret = self.get_client_secret(session_name, client_name)?;
+
+        // Valid if we have a secret for this client and it matches the supplied value
+        match actual_secret {
+            Some(actual_secret) => Ok(constant_time_eq(&actual_secret, client_secret)),
+            None => Ok(false),
+        }
+    }
+
+    /// Does some session already have this client?
+    fn session_has_client(&self, session_name: &str, client_name: &str) -> Result<bool> {
+        // Present if we have a secret registered for them
+        Ok(self.get_client_secret(session_name, client_name)?.is_some())
+    }
+
+    /// How full a session is, or None if it doesn't exist
+    fn get_occupancy(&self, session_name: &str) -> Result<Option<Occupancy>> {
+        let Some(options) = self.get_session_options(session_name)? else {
+            return Ok(None);
+        };
+
+        let clients = self.get_clients(session_name)?;
+        let waiting = clients.iter().filter(|(_, status)| *status == ClientStatus::Waiting).count();
+
+        Ok(Some(Occupancy {
+            clients: clients.len() - waiting,
+            waiting,
+            max_clients: options.max_clients,
+        }))
+    }
+
+    /// Checks a join password against the session's, if it has one. Too many wrong guesses from an
+    /// address and the session stops checking its guesses for a while, right or wrong. Only that
+    /// address's though, so nobody can lock everyone else out by guessing
+    fn check_join_password(&self, session_name: &str, password: Option<&str>, address: Option<&str>) -> Result<PasswordCheck> {
+        let Some(options) = self.get_session_options(session_name)? else {
+            return Err(ApiError::NoSuchSession.into());
+        };
+
+        let Some(password_hash) = options.password_hash else {
+            return Ok(PasswordCheck::Correct);
+        };
+
+        let key = failed_join_key(session_name, address);
+        if self.get_counter(&key)? >= MAX_FAILED_JOINS {
+            return Ok(PasswordCheck::Throttled);
+        }
+
+        if verify_password(password.unwrap_or_default(), &password_hash) {
+            return Ok(PasswordCheck::Correct);
+        }
+
+        self.increment_counter(&key, FAILED_JOIN_WINDOW_SECONDS)?;
+        Ok(PasswordCheck::Wrong)
+    }
+
+    /// Is this client name, or the address they're coming from, banned from the session?
+    fn is_join_banned(&self, session_name: &str, client_name: &str, address: Option<&str>) -> Result<bool> {
+        if self.is_banned(session_name, &ban_entry("name", client_name))? {
+            return Ok(true);
+        }
+
+        match address {
+            Some(address) => self.is_banned(session_name, &ban_entry("address", address)),
+            None => Ok(false),
+        }
+    }
+
+    /// Registers client_name in a session if nobody there already has it: Pending if there's room,
+    /// Waiting if it's full but keeps a waiting list. Returns their secret and status, or None if
+    /// it's full and there's nowhere for them to wait.
+    /// This checks and registers in separate steps, so two joins racing for the same name or the
+    /// last place can both get it. Backends that can do it in one step override it
+    fn claim_client(&self, session_name: &str, client_name: &str) -> Result<Option<(String, ClientStatus)>> {
+        if self.session_has_client(session_name, client_name)? {
+            return Err(ApiError::NameTaken.into());
+        }
+
+        let Some(options) = self.get_session_options(session_name)? else {
+            return Err(ApiError::NoSuchSession.into());
+        };
+        let occupancy = self.get_occupancy(session_name)?.ok_or(ApiError::NoSuchSession)?;
+
+        let status = match (occupancy.is_full(), options.waiting_list) {
+            (false, _) => ClientStatus::Pending,
+            (true, true) => ClientStatus::Waiting,
+            (true, false) => return Ok(None),
+        };
+
+        let client_secret = self.register_client_secret(session_name, client_name, status)?;
+        Ok(Some((client_secret, status)))
+    }
+
+    /// Initiates a client joining a session, sending them on to the host or putting them in line
+    fn initiate_join(
+        &self,
+        session_name: &str,
+        client_name: &str,
+        rtc_offer: &str,
+        address: Option<&str>,
+    ) -> Result<JoinOutcome> {
+        let Some((client_secret, status)) = self.claim_client(session_name, client_name)? else {
+            return Ok(JoinOutcome::Full);
+        };
+        if let Some(address) = address {
+            self.set_client_address(session_name, client_name, address)?;
+        }
+
+        if status == ClientStatus::Waiting {
+            let position = self.enqueue_waiting(session_name, client_name, rtc_offer)?;
+            self.notify_client(session_name, client_name, &SignalMessage::Waiting { position })?;
+
+            let client_secret = self.issue_secret(session_name, Some(client_name), client_secret);
+            return Ok(JoinOutcome::Waiting { client_secret, position });
+        }
+
+        // Forward to the session host, now that there's someone for them to answer
+        let start_join = SignalMessage::StartJoin {
+            client_name: client_name.into(),
+            client_offer: rtc_offer.into(),
+        };
+        if let Err(e) = self.push_message_to_host(session_name, &start_join) {
+            // The host will never hear of them, so don't leave them holding the name
+            self.remove_client(session_name, client_name)?;
+            return Err(e);
+        }
+
+        Ok(JoinOutcome::Pending(self.issue_secret(session_name, Some(client_name), client_secret)))
+    }
+
+    /// Sends clients from the front of the waiting list on to the host while there's room
+    fn promote_waiting(&self, session_name: &str) -> Result<()> {
+        let Some(mut occupancy) = self.get_occupancy(session_name)? else {
+            return Ok(());
+        };
+
+        while !occupancy.is_full() {
+            // No room to tell the host, so they keep their place until the next time someone leaves
+            if self.mailbox_length(&Mailbox::Host { session_name })? >= self.config().max_mailbox_length {
+                break;
+            }
+
+            let Some((client_name, rtc_offer)) = self.dequeue_waiting(session_name)? else {
+                break;
+            };
+
+            // They may have left or expired while waiting
+            if self.get_client_status(session_name, &client_name)? != Some(ClientStatus::Waiting) {
+                continue;
+            }
+
+            // Whoever made room shouldn't be turned away because the host is behind
+            self.set_client_status(session_name, &client_name, ClientStatus::Pending)?;
+            self.notify_host(session_name, &SignalMessage::StartJoin {
+                client_name: client_name.clone(),
+                client_offer: rtc_offer,
+            })?;
+            self.notify_client(session_name, &client_name, &SignalMessage::Promoted)?;
+
+            occupancy.clients += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Host throws a client out, whether they're in, pending or waiting, and optionally bans their
+    /// name and/or address from coming back. Anything left in their mailbox is dropped, and their
+    /// secret is revoked once they've had a chance to read that they were kicked.
+    /// Returns false if there's no such client
+    fn kick_client(
+        &self,
+        session_name: &str,
+        client_name: &str,
+        reason: Option<&str>,
+        ban: bool,
+        ban_address: bool,
+    ) -> Result<bool> {
+        let Some(status) = self.get_client_status(session_name, client_name)? else {
+            return Ok(false);
+        };
+
+        if ban {
+            self.add_ban(session_name, &ban_entry("name", client_name))?;
+        }
+        if ban_address {
+            if let Some(address) = self.get_client_address(session_name, client_name)? {
+                self.add_ban(session_name, &ban_entry("address", &address))?;
+            }
+        }
+
+        if status == ClientStatus::Waiting {
+            self.remove_waiting(session_name, client_name)?;
+        }
+
+        let mailbox = Mailbox::Client { session_name, client_name };
+        self.trim_mailbox(&mailbox, 0)?;
+        self.drop_held_candidates(session_name, client_name)?;
+        self.notify_client(session_name, client_name, &SignalMessage::Kicked { reason: reason.map(String::from) })?;
+        self.revoke_client_token(session_name, client_name, CLOSED_SESSION_GRACE_SECONDS)?;
+        self.expire_client(session_name, client_name, CLOSED_SESSION_GRACE_SECONDS)?;
+
+        if status != ClientStatus::Waiting {
+            self.promote_waiting(session_name)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Ends a session, letting every client know.
+    /// Clients keep their secret and mailbox for a little while so they can read that it's over
+    fn close_session(&self, session_name: &str) -> Result<()> {
+        for (client_name, _) in self.get_clients(session_name)? {
+            self.notify_client(session_name, &client_name, &SignalMessage::SessionClosed)?;
+            self.drop_held_candidates(session_name, &client_name)?;
+            self.revoke_client_token(session_name, &client_name, CLOSED_SESSION_GRACE_SECONDS)?;
+            self.expire_client(session_name, &client_name, CLOSED_SESSION_GRACE_SECONDS)?;
+        }
+
+        if self.config().token_key.is_some() {
+            if let Some(token_id) = self.get_host_secret(session_name)? {
+                self.deny_token(&token_id, self.now())?;
+            }
+        }
+
+        self.delete_session(session_name)
+    }
+
+    /// A client is leaving a session, let the host know and make room for whoever is waiting
+    fn leave_session(&self, session_name: &str, client_name: &str) -> Result<()> {
+        let status = self.get_client_status(session_name, client_name)?;
+        self.revoke_client_token(session_name, client_name, 0)?;
+        self.remove_client(session_name, client_name)?;
+        self.drop_held_candidates(session_name, client_name)?;
+
+        // Nobody to tell if the session already closed
+        if !self.has_session(session_name)? {
+            return Ok(());
+        }
+
+        let Some(status) = status else {
+            // Already kicked, rejected or closed out and just reading why, the host knows they're gone
+            return Ok(());
+        };
+
+        // The host never heard about anyone still waiting
+        if status == ClientStatus::Waiting {
+            return self.remove_waiting(session_name, client_name);
+        }
+
+        self.notify_host(session_name, &SignalMessage::ClientLeft { client_name: client_name.into() })?;
+
+        self.promote_waiting(session_name)
+    }
+
+    /// Host lets a pending client in, passing on any candidates they've sent in the meantime.
+    /// Returns false if they weren't waiting to join
+    fn accept_client(&self, session_name: &str, client_name: &str) -> Result<bool> {
+        if self.get_client_status(session_name, client_name)? != Some(ClientStatus::Pending) {
+            return Ok(false);
+        }
+
+        // The host already has the offer, so these can follow it now. They're the client's messages
+        // like any other, and go first so a full mailbox turns the host away before anything changes
+        let held = Mailbox::HeldForHost { session_name, client_name };
+        let (candidates, last_id) = self.held_messages(&held)?;
+        if let Some(last_id) = last_id {
+            self.deliver(&Mailbox::Host { session_name }, &candidates, self.config().mailbox_overflow)?;
+            self.ack_messages(&held, &last_id)?;
+        }
+
+        self.set_client_status(session_name, client_name, ClientStatus::Accepted)?;
+        self.notify_client(session_name, client_name, &SignalMessage::JoinAccepted)?;
+
+        Ok(true)
+    }
+
+    /// Host turns a pending client away, revoking their secret once they've had a chance to read why.
+    /// Returns false if they weren't waiting to join
+    fn reject_client(&self, session_name: &str, client_name: &str, reason: Option<&str>) -> Result<bool> {
+        if self.get_client_status(session_name, client_name)? != Some(ClientStatus::Pending) {
+            return Ok(false);
+        }
+
+        self.notify_client(session_name, client_name, &SignalMessage::JoinRejected {
+            reason: reason.map(String::from),
+        })?;
+        self.drop_held_candidates(session_name, client_name)?;
+        self.revoke_client_token(session_name, client_name, CLOSED_SESSION_GRACE_SECONDS)?;
+        self.expire_client(session_name, client_name, CLOSED_SESSION_GRACE_SECONDS)?;
+        self.promote_waiting(session_name)?;
+
+        Ok(true)
+    }
+
+    /// Send ice candidates (or the end of them) from a client to a host, holding them back until
+    /// the host has let the client in. Returns how many old messages were dropped to make room.
+    /// Assumes we are already authenticated
+    fn client_ice_candidates(&self, session_name: &str, client_name: &str, messages: &[SignalMessage]) -> Result<usize> {
+        let overflow = self.config().mailbox_overflow;
+
+        match self.get_client_status(session_name, client_name)? {
+            Some(ClientStatus::Accepted | ClientStatus::Answered) => {
+                self.deliver(&Mailbox::Host { session_name }, messages, overflow)
+            },
+            Some(ClientStatus::Pending | ClientStatus::Waiting) => {
+                self.deliver(&Mailbox::HeldForHost { session_name, client_name }, messages, overflow)
+            },
+            None => Err(ApiError::JoinNotAccepted.into()),
+        }
+    }
+
+    /// Send ice candidates (or the end of them) from a host to a client, holding them back until
+    /// the host's answer has been sent. Returns how many old messages were dropped to make room.
+    /// Assumes we are already authenticated
+    fn host_ice_candidates(&self, session_name: &str, client_name: &str, messages: &[SignalMessage]) -> Result<usize> {
+        self.push_messages_to_client(session_name, client_name, messages)
+    }
+
+    /// Adds a message to the host's message queue/mailbox, as mailbox_overflow says. Returns how
+    /// many old messages were dropped to make room
+    fn push_message_to_host(&self, session_name: &str, message: &SignalMessage) -> Result<usize> {
+        let overflow = self.config().mailbox_overflow;
+        self.deliver(&Mailbox::Host { session_name }, std::slice::from_ref(message), overflow)
+    }
+
+    /// Lets the host know about something we did, see notify
+    fn notify_host(&self, session_name: &str, message: &SignalMessage) -> Result<()> {
+        self.notify(&Mailbox::Host { session_name }, message, self.config().mailbox_overflow)
+    }
+
+    fn get_messages_for_host(&self, session_name: &str, since: Option<&str>, wait_seconds: i64) -> Result<Vec<MailboxMessage>> {
+        self.poll_messages(&Mailbox::Host { session_name }, since, wait_seconds)
+    }
+
+    /// Adds messages from the host to a client's message queue/mailbox, as mailbox_overflow says.
+    /// Candidates sent before the answer are held back, and follow it once it's sent. The answer
+    /// lets the client in if the host hadn't yet. Returns how many old messages were dropped to
+    /// make room
+    fn push_messages_to_client(&self, session_name: &str, client_name: &str, messages: &[SignalMessage]) -> Result<usize> {
+        let overflow = self.config().mailbox_overflow;
+        let mailbox = Mailbox::Client { session_name, client_name };
+        let held = Mailbox::HeldForClient { session_name, client_name };
+
+        let status = self.get_client_status(session_name, client_name)?;
+        if status == Some(ClientStatus::Answered) {
+            return self.deliver(&mailbox, messages, overflow);
+        }
+
+        let Some(answer) = messages.iter().position(|message| matches!(message, SignalMessage::Answer { .. })) else {
+            // Nothing to hold the candidates for yet, anything else can go now
+            let (candidates, others): (Vec<_>, Vec<_>) = messages.iter().cloned().partition(SignalMessage::is_candidate);
+            if !candidates.is_empty() {
+                self.deliver(&held, &candidates, overflow)?;
+            }
+            return if others.is_empty() { Ok(0) } else { self.deliver(&mailbox, &others, overflow) };
+        };
+
+        // Answering a client is as good as letting them in, hosts from before decisions don't do
+        // it separately. Clients still in line haven't had their offer passed on to answer though
+        match status {
+            Some(ClientStatus::Accepted) => {},
+            Some(ClientStatus::Pending) => {
+                self.accept_client(session_name, client_name)?;
+            },
+            _ => return Err(ApiError::JoinNotAccepted.into()),
+        }
+
+        let capacity = self.mailbox_capacity(overflow);
+        if messages.len() > capacity {
+            return Err(ApiError::PayloadTooLarge(format!("At most {capacity} messages can be sent at once")).into());
+        }
+
+        // Everything has to fit in the mailbox at once, so held candidates that wouldn't are dropped,
+        // oldest first. Otherwise the answer could never be sent
+        let held_dropped = self.trim_mailbox(&held, capacity - messages.len())?;
+
+        // Up to and including the answer, then every candidate that was waiting on it, then the rest
+        let (before, after) = messages.split_at(answer + 1);
+        let (candidates, mut ordered): (Vec<_>, Vec<_>) = before.iter().cloned().partition(SignalMessage::is_candidate);
+        let (held_candidates, last_id) = self.held_messages(&held)?;
+        ordered.extend(held_candidates);
+        ordered.extend(candidates);
+        ordered.extend_from_slice(after);
+
+        let dropped = held_dropped + self.deliver(&mailbox, &ordered, overflow)?;
+        if let Some(last_id) = last_id {
+            self.ack_messages(&held, &last_id)?;
+        }
+        self.set_client_status(session_name, client_name, ClientStatus::Answered)?;
+
+        Ok(dropped)
+    }
+
+    /// Everything in one of the Held mailboxes, in the order it was sent, and the id of the last
+    /// of it. Only that much should be acknowledged once it's passed on, anything sent since the
+    /// read stays held rather than being lost
+    fn held_messages(&self, mailbox: &Mailbox) -> Result<(Vec<SignalMessage>, Option<String>)> {
+        let mut messages = Vec::new();
+        let mut since = None;
+        loop {
+            let batch = self.read_messages(mailbox, since.as_deref(), 0)?;
+            let Some(last) = batch.last() else {
+                return Ok((messages, since));
+            };
+            since = Some(last.id.clone());
+
+            for message in batch {
+                messages.push(SignalMessage::parse(message.message)?);
+            }
+        }
+    }
+
+    /// Forgets any candidates held back for or from a client who's gone
+    fn drop_held_candidates(&self, session_name: &str, client_name: &str) -> Result<()> {
+        self.trim_mailbox(&Mailbox::HeldForHost { session_name, client_name }, 0)?;
+        self.trim_mailbox(&Mailbox::HeldForClient { session_name, client_name }, 0)?;
+        Ok(())
+    }
+
+    /// Lets a client know about something we did, see notify. Their mailbox only ever holds what's
+    /// meant for them, so making room there can't cost anyone else anything
+    fn notify_client(&self, session_name: &str, client_name: &str, message: &SignalMessage) -> Result<()> {
+        self.notify(&Mailbox::Client { session_name, client_name }, message, MailboxOverflow::DropOldest)
+    }
+
+    /// Passes on something the server has to say. This can use the room senders leave free, so
+    /// it doesn't push out anything waiting to be read, like another client's start_join. Only
+    /// past that does overflow come into it
+    fn notify(&self, mailbox: &Mailbox, message: &SignalMessage, overflow: MailboxOverflow) -> Result<()> {
+        let max_length = self.config().max_mailbox_length;
+        if self.mailbox_length(mailbox)? >= max_length {
+            match overflow {
+                MailboxOverflow::Reject => {
+                    return Err(ApiError::MailboxFull { retry_after: self.config().poll_timeout_seconds }.into());
+                },
+                MailboxOverflow::DropOldest => {
+                    self.trim_mailbox(mailbox, max_length - 1)?;
+                },
+            }
+        }
+
+        self.record_activity(mailbox.session_name())?;
+        self.push_message(mailbox, &message.to_value())
+    }
+
+    /// How full senders can make a mailbox. Turning them away leaves a tenth of it (at least one
+    /// message) for notifications, dropping the oldest just makes room for them like anything else
+    fn mailbox_capacity(&self, overflow: MailboxOverflow) -> usize {
+        let max_length = self.config().max_mailbox_length;
+        match overflow {
+            MailboxOverflow::Reject => max_length - (max_length / 10).max(1).min(max_length - 1),
+            MailboxOverflow::DropOldest => max_length,
+        }
+    }
+
+    fn get_messages_for_client(
+        &self,
+        session_name: &str,
+        client_name: &str,
+        since: Option<&str>,
+        wait_seconds: i64,
+    ) -> Result<Vec<MailboxMessage>> {
+        self.poll_messages(&Mailbox::Client { session_name, client_name }, since, wait_seconds)
+    }
+
+    /// Reads the messages after since, having a cursor means the poller has everything up to
+    /// there so it's acknowledged at the same time
+    fn poll_messages(&self, mailbox: &Mailbox, since: Option<&str>, wait_seconds: i64) -> Result<Vec<MailboxMessage>> {
+        if let Some(since) = since {
+            self.ack_messages(mailbox, since)?;
+        }
+
+        self.read_messages(mailbox, since, wait_seconds)
+    }
+
+    /// Adds messages to a mailbox, keeping it within mailbox_capacity by turning them away or
+    /// dropping the oldest messages. Returns how many were dropped
+    fn deliver(&self, mailbox: &Mailbox, messages: &[SignalMessage], overflow: MailboxOverflow) -> Result<usize> {
+        let capacity = self.mailbox_capacity(overflow);
+        if messages.len() > capacity {
+            return Err(ApiError::PayloadTooLarge(format!("At most {capacity} messages can be sent at once")).into());
+        }
+
+        let mut dropped = 0;
+        if self.mailbox_length(mailbox)? + messages.len() > capacity {
+            dropped = match overflow {
+                MailboxOverflow::Reject => {
+                    return Err(ApiError::MailboxFull { retry_after: self.config().poll_timeout_seconds }.into());
+                },
+                MailboxOverflow::DropOldest => self.trim_mailbox(mailbox, capacity - messages.len())?,
+            };
+        }
+
+        self.record_activity(mailbox.session_name())?;
+
+        for message in messages {
+            self.push_message(mailbox, &message.to_value())?;
+        }
+
+        Ok(dropped)
+    }
+
+    /// Counts a message sent in the session, see is_session_active
+    fn record_activity(&self, session_name: &str) -> Result<()> {
+        let window = self.now() / ACTIVITY_WINDOW_SECONDS;
+        self.increment_counter(&session_activity_key(session_name, window), 2 * ACTIVITY_WINDOW_SECONDS)?;
+        Ok(())
+    }
+
+    /// Has anything been sent in the session lately, i.e. is someone in the middle of joining?
+    /// Lately being within the last one or two ACTIVITY_WINDOW_SECONDS
+    fn is_session_active(&self, session_name: &str) -> Result<bool> {
+        let window = self.now() / ACTIVITY_WINDOW_SECONDS;
+
+        Ok(self.get_counter(&session_activity_key(session_name, window))? > 0
+            || self.get_counter(&session_activity_key(session_name, window - 1))? > 0)
+    }
+}
@@ -0,0 +1,63 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+/// Tokens look like v1.<payload>.<signature>, so they can't be mistaken for a plain secret
+const VERSION: &str = "v1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Host,
+    Client,
+}
+
+/// What a token vouches for
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    pub session_name: String,
+    pub role: Role,
+    /// Only for clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_name: Option<String>,
+    pub expires_at: i64,
+    /// The secret the store generated for the host or client, which names the token on the deny-list
+    pub id: String,
+}
+
+/// Signs the claims with key
+pub fn issue(key: &str, claims: &Claims) -> String {
+    // Nothing in Claims can fail to serialize
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap_or_default());
+    let signature = URL_SAFE_NO_PAD.encode(sign(key, &payload).finalize().into_bytes());
+
+    format!("{VERSION}.{payload}.{signature}")
+}
+
+/// The claims of a token signed with key that hasn't expired by now, or None. Doesn't check
+/// the deny-list, that's up to the store
+pub fn verify(key: &str, token: &str, now: i64) -> Option<Claims> {
+    let (payload, signature) = token.strip_prefix(VERSION)?.strip_prefix('.')?.split_once('.')?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+
+    // Constant time, so the signature can't be guessed a byte at a time
+    sign(key, payload).verify_slice(&signature).ok()?;
+
+    let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    (claims.expires_at > now).then_some(claims)
+}
+
+fn sign(key: &str, payload: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(VERSION.as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+/// Compares secrets without giving away how much of them matched
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
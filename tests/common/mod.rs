@@ -0,0 +1,91 @@
+//! Helpers shared by the integration tests, which drive the HTTP handlers on the host with an
+//! in-memory store, no Spin runtime or Redis needed
+
+// Every test file is its own crate, and none of them use all of these
+#![allow(dead_code)]
+
+use bytes::Bytes;
+use rust_signalling::{config::Config, handle_request, memory_store::MemoryStore};
+use serde_json::{json, Value};
+use spin_sdk::http::{Request, Response};
+
+pub fn request(method: &str, uri: &str, body: Option<Value>) -> Request {
+    http::Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(body.map(|body| Bytes::from(body.to_string())))
+        .unwrap()
+}
+
+/// Has the server handle req, which is expected to get some response
+pub fn send(store: &MemoryStore, config: &Config, req: Request) -> Response {
+    handle_request(store, config, req).unwrap()
+}
+
+pub fn post(store: &MemoryStore, path: &str, body: Value) -> Response {
+    send(store, &Config::default(), request("POST", path, Some(body)))
+}
+
+pub fn get(store: &MemoryStore, uri: &str) -> Response {
+    send(store, &Config::default(), request("GET", uri, None))
+}
+
+/// Adds a secret to req as an Authorization header
+pub fn bearer(mut req: Request, secret: &str) -> Request {
+    req.headers_mut().insert("Authorization", format!("Bearer {secret}").parse().unwrap());
+    req
+}
+
+/// GET with a secret in the Authorization header
+pub fn get_as(store: &MemoryStore, uri: &str, secret: &str) -> Response {
+    send(store, &Config::default(), bearer(request("GET", uri, None), secret))
+}
+
+pub fn json_body(res: &Response) -> Value {
+    let body = res.body().as_ref().expect("response has a body");
+    serde_json::from_slice(body).unwrap()
+}
+
+/// Pulls the messages themselves out of a mailbox response
+pub fn messages(res: &Response) -> Vec<Value> {
+    json_body(res)["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|message| message["message"].clone())
+        .collect()
+}
+
+pub fn assert_error(res: &Response, status: u16, code: &str) {
+    assert_eq!(res.status(), status);
+    assert_eq!(json_body(res)["error"]["code"], code);
+}
+
+/// Starts a session, returning its name and the host secret
+pub fn host(store: &MemoryStore, public: bool) -> (String, String) {
+    host_with(store, json!({ "public": public, "host_name": "Alice" }))
+}
+
+/// Starts a session with whatever options, returning its name and the host secret
+pub fn host_with(store: &MemoryStore, body: Value) -> (String, String) {
+    let res = post(store, "/host", body);
+    assert_eq!(res.status(), 200);
+
+    let body = json_body(&res);
+    (
+        body["session_name"].as_str().unwrap().into(),
+        body["host_secret"].as_str().unwrap().into(),
+    )
+}
+
+/// Has a client join, returning their secret
+pub fn join(store: &MemoryStore, session_name: &str, client_name: &str) -> String {
+    let res = post(store, "/join", json!({
+        "session_name": session_name,
+        "client_name": client_name,
+        "rtc_offer": "offer",
+    }));
+    assert_eq!(res.status(), 200);
+
+    json_body(&res)["client_secret"].as_str().unwrap().into()
+}
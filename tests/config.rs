@@ -0,0 +1,137 @@
+//! Parsing of the Spin variables into a Config
+
+use std::collections::HashMap;
+
+use rust_signalling::config::{Config, MailboxOverflow, StoreBackend};
+
+fn config(vars: &[(&str, &str)]) -> anyhow::Result<Config> {
+    let vars: HashMap<String, String> = vars.iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+
+    Config::from_lookup(|key| vars.get(key).cloned())
+}
+
+#[test]
+fn defaults_fill_in_anything_missing() {
+    let config = config(&[("redis_address", "redis://localhost:6379")]).unwrap();
+
+    assert_eq!(config.store, StoreBackend::Redis);
+    assert_eq!(config.session_ttl_seconds, 600);
+    assert_eq!(config.secret_length, 16);
+    assert_eq!(config.poll_timeout_seconds, 5);
+    assert_eq!(config.max_poll_wait_seconds, 25);
+    assert_eq!(config.message_batch_size, 100);
+    assert_eq!(config.max_sdp_bytes, 32_768);
+    assert_eq!(config.max_mailbox_length, 500);
+    assert_eq!(config.mailbox_overflow, MailboxOverflow::Reject);
+    assert_eq!(config.stream_timeout_seconds, 25);
+    assert_eq!(config.cors_origins, vec!["*"]);
+    assert_eq!(config.cors_allow_headers, vec!["Authorization", "Content-Type"]);
+    assert_eq!(config.cors_max_age_seconds, 600);
+    assert!(!config.cors_allow_credentials);
+    assert!(config.allow_query_secrets);
+    assert_eq!(config.token_key, None);
+    assert_eq!(config.token_ttl_seconds, 86_400);
+    assert_eq!(config.client_ip_header, "spin-client-addr");
+    assert_eq!(config.rate_limit_window_seconds, 60);
+    assert_eq!(config.rate_limit_sessions_per_ip, 10);
+}
+
+#[test]
+fn values_are_parsed() {
+    let config = config(&[
+        ("store", "key_value"),
+        ("session_ttl_seconds", "1200"),
+        ("secret_length", "32"),
+        ("poll_timeout_seconds", "10"),
+        ("max_poll_wait_seconds", "20"),
+        ("message_batch_size", "10"),
+        ("max_sdp_bytes", "4096"),
+        ("max_candidates_per_message", "5"),
+        ("max_candidate_bytes", "256"),
+        ("max_message_bytes", "8192"),
+        ("max_mailbox_length", "20"),
+        ("mailbox_overflow", "drop_oldest"),
+        ("stream_timeout_seconds", "60"),
+        ("cors_origins", "https://a.example, https://b.example"),
+        ("cors_allow_headers", "Authorization, X-Custom"),
+        ("cors_max_age_seconds", "60"),
+        ("cors_allow_credentials", "true"),
+        ("allow_query_secrets", "false"),
+        ("token_key", "0123456789abcdef0123456789abcdef"),
+        ("token_ttl_seconds", "3600"),
+        ("client_ip_header", "X-Forwarded-For"),
+        ("rate_limit_window_seconds", "10"),
+        ("rate_limit_joins_per_session", "0"),
+    ]).unwrap();
+
+    assert_eq!(config.store, StoreBackend::KeyValue);
+    assert_eq!(config.session_ttl_seconds, 1200);
+    assert_eq!(config.secret_length, 32);
+    assert_eq!(config.poll_timeout_seconds, 10);
+    assert_eq!(config.max_poll_wait_seconds, 20);
+    assert_eq!(config.message_batch_size, 10);
+    assert_eq!(config.max_sdp_bytes, 4096);
+    assert_eq!(config.max_candidates_per_message, 5);
+    assert_eq!(config.max_candidate_bytes, 256);
+    assert_eq!(config.max_message_bytes, 8192);
+    assert_eq!(config.max_mailbox_length, 20);
+    assert_eq!(config.mailbox_overflow, MailboxOverflow::DropOldest);
+    assert_eq!(config.stream_timeout_seconds, 60);
+    assert!(config.allows_origin("https://b.example"));
+    assert!(!config.allows_origin("https://c.example"));
+    assert_eq!(config.cors_allow_headers, vec!["Authorization", "X-Custom"]);
+    assert_eq!(config.cors_max_age_seconds, 60);
+    assert!(config.cors_allow_credentials);
+    assert!(!config.allow_query_secrets);
+    assert_eq!(config.token_key.as_deref(), Some("0123456789abcdef0123456789abcdef"));
+    assert_eq!(config.token_ttl_seconds, 3600);
+    assert_eq!(config.client_ip_header, "X-Forwarded-For");
+    assert_eq!(config.rate_limit_window_seconds, 10);
+    assert_eq!(config.rate_limit_joins_per_session, 0);
+}
+
+#[test]
+fn redis_requires_an_address() {
+    let err = config(&[("store", "redis")]).unwrap_err();
+    assert!(err.to_string().contains("redis_address"));
+
+    let err = config(&[("redis_address", "localhost:6379")]).unwrap_err();
+    assert!(err.to_string().contains("redis_address"));
+    assert!(!err.to_string().contains("localhost"));
+}
+
+#[test]
+fn invalid_values_are_rejected() {
+    let base = [("store", "key_value")];
+
+    for (key, value) in [
+        ("store", "postgres"),
+        ("session_ttl_seconds", "forever"),
+        ("session_ttl_seconds", "0"),
+        ("secret_length", "4"),
+        ("poll_timeout_seconds", "60"),
+        // Less than poll_timeout_seconds
+        ("max_poll_wait_seconds", "2"),
+        ("message_batch_size", "0"),
+        ("max_sdp_bytes", "100"),
+        ("max_mailbox_length", "0"),
+        ("mailbox_overflow", "drop_newest"),
+        ("cors_max_age_seconds", "-1"),
+        ("cors_allow_credentials", "yes"),
+        // Credentials can't go to just anyone
+        ("cors_allow_credentials", "true"),
+        ("token_key", "too short"),
+        ("token_ttl_seconds", "10"),
+        ("rate_limit_window_seconds", "0"),
+        ("rate_limit_requests_per_ip", "-1"),
+    ] {
+        let mut vars = base.to_vec();
+        vars.push((key, value));
+
+        let err = config(&vars).unwrap_err();
+        assert!(err.to_string().contains(key), "{key}={value} gave {err}");
+        assert!(key != "token_key" || !err.to_string().contains(value), "token_key was echoed back");
+    }
+}
@@ -0,0 +1,82 @@
+//! The CORS policy, as a browser would see it
+
+mod common;
+
+use common::send;
+use rust_signalling::{config::Config, memory_store::MemoryStore};
+use serde_json::{json, Value};
+use spin_sdk::http::{Request, Response};
+
+fn request(method: &str, uri: &str, origin: Option<&str>, body: Option<Value>) -> Request {
+    let mut req = common::request(method, uri, body);
+    if let Some(origin) = origin {
+        req.headers_mut().insert("Origin", origin.parse().unwrap());
+    }
+    req
+}
+
+fn preflight(config: &Config, uri: &str, origin: &str) -> Response {
+    let mut req = request("OPTIONS", uri, Some(origin), None);
+    req.headers_mut().insert("Access-Control-Request-Method", "POST".parse().unwrap());
+    req.headers_mut().insert("Access-Control-Request-Headers", "authorization, content-type".parse().unwrap());
+
+    send(&MemoryStore::new(), config, req)
+}
+
+fn allowlist() -> Config {
+    Config {
+        cors_origins: vec!["https://app.example".into()],
+        ..Config::default()
+    }
+}
+
+#[test]
+fn preflights_say_what_is_allowed() {
+    let res = preflight(&Config::default(), "/sessions", "https://anywhere.example");
+    assert_eq!(res.status(), 204);
+
+    let headers = res.headers();
+    assert_eq!(headers["Access-Control-Allow-Origin"], "*");
+    assert_eq!(headers["Access-Control-Allow-Methods"], "POST, GET, HEAD, OPTIONS");
+    assert_eq!(headers["Access-Control-Allow-Headers"], "Authorization, Content-Type");
+    assert_eq!(headers["Access-Control-Max-Age"], "600");
+    assert!(!headers.contains_key("Access-Control-Allow-Credentials"));
+}
+
+#[test]
+fn only_listed_origins_are_allowed() {
+    let config = allowlist();
+
+    let res = preflight(&config, "/sessions", "https://app.example");
+    assert_eq!(res.headers()["Access-Control-Allow-Origin"], "https://app.example");
+    assert_eq!(res.headers()["Vary"], "Origin");
+
+    let res = preflight(&config, "/sessions", "https://evil.example");
+    assert!(!res.headers().contains_key("Access-Control-Allow-Origin"));
+    assert!(!res.headers().contains_key("Access-Control-Allow-Methods"));
+    assert_eq!(res.headers()["Vary"], "Origin");
+}
+
+#[test]
+fn errors_carry_cors_headers_too() {
+    let store = MemoryStore::new();
+    let config = allowlist();
+
+    let req = request("POST", "/host", Some("https://app.example"), Some(json!({})));
+    let res = send(&store, &config, req);
+    assert_eq!(res.status(), 400);
+    assert_eq!(res.headers()["Access-Control-Allow-Origin"], "https://app.example");
+}
+
+#[test]
+fn credentials_can_be_allowed() {
+    let config = Config {
+        cors_allow_credentials: true,
+        ..allowlist()
+    };
+
+    let req = request("GET", "/sessions", Some("https://app.example"), None);
+    let res = send(&MemoryStore::new(), &config, req);
+    assert_eq!(res.headers()["Access-Control-Allow-Origin"], "https://app.example");
+    assert_eq!(res.headers()["Access-Control-Allow-Credentials"], "true");
+}
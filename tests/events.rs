@@ -0,0 +1,136 @@
+//! Mailboxes read as text/event-stream
+
+mod common;
+
+use common::{bearer, get_as, json_body, post, request, send};
+use rust_signalling::{config::Config, memory_store::MemoryStore};
+use serde_json::{json, Value};
+use spin_sdk::http::Response;
+use urlencoding::encode;
+
+fn stream(store: &MemoryStore, uri: &str, secret: &str, last_event_id: Option<&str>) -> Response {
+    let mut req = bearer(request("GET", uri, None), secret);
+    req.headers_mut().insert("Accept", "text/event-stream".parse().unwrap());
+    if let Some(id) = last_event_id {
+        req.headers_mut().insert("Last-Event-ID", id.parse().unwrap());
+    }
+
+    send(store, &Config::default(), req)
+}
+
+fn body_text(res: &Response) -> String {
+    String::from_utf8(res.body().as_ref().unwrap().to_vec()).unwrap()
+}
+
+/// (id, data) of each event in the stream, ignoring comments and the retry field
+fn events(res: &Response) -> Vec<(String, Value)> {
+    body_text(res)
+        .split("\n\n")
+        .filter_map(|event| {
+            let mut id = None;
+            let mut data = None;
+            for line in event.lines() {
+                if let Some(value) = line.strip_prefix("id: ") {
+                    id = Some(value.to_string());
+                } else if let Some(value) = line.strip_prefix("data: ") {
+                    data = Some(serde_json::from_str(value).unwrap());
+                }
+            }
+            Some((id?, data?))
+        })
+        .collect()
+}
+
+/// Starts a session with Bob waiting to get in, returning its name and the host's secret
+fn session_with_join(store: &MemoryStore) -> (String, String) {
+    let body = json_body(&post(store, "/sessions", json!({ "public": false, "host_name": "Alice" })));
+    let session_name = body["session_name"].as_str().unwrap().to_string();
+    let host_secret = body["host_secret"].as_str().unwrap().to_string();
+
+    let res = post(store, &format!("/sessions/{}/clients", encode(&session_name)), json!({ "client_name": "Bob", "rtc_offer": "offer" }));
+    assert_eq!(res.status(), 200);
+
+    (session_name, host_secret)
+}
+
+#[test]
+fn messages_arrive_as_events() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = session_with_join(&store);
+    let uri = format!("/sessions/{}/messages", encode(&session_name));
+
+    let res = stream(&store, &uri, &host_secret, None);
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["Content-Type"], "text/event-stream");
+    assert_eq!(res.headers()["Cache-Control"], "no-cache");
+    assert!(body_text(&res).starts_with("retry: "));
+
+    let events = events(&res);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].1["type"], "start_join");
+    assert_eq!(events[0].1["client_name"], "Bob");
+
+    // Anyone not asking for a stream still gets JSON
+    let body = json_body(&get_as(&store, &uri, &host_secret));
+    assert_eq!(body["messages"][0]["id"], events[0].0.as_str());
+}
+
+#[test]
+fn reconnecting_resumes_after_the_last_event() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = session_with_join(&store);
+    let uri = format!("/sessions/{}/messages", encode(&session_name));
+
+    let (last_event_id, _) = events(&stream(&store, &uri, &host_secret, None)).remove(0);
+
+    post(&store, &format!("/sessions/{}/clients", encode(&session_name)), json!({ "client_name": "Carol", "rtc_offer": "offer" }));
+
+    let resumed = events(&stream(&store, &uri, &host_secret, Some(&last_event_id)));
+    assert_eq!(resumed.len(), 1);
+    assert_eq!(resumed[0].1["client_name"], "Carol");
+
+    // Having seen Bob's join, it's been acknowledged
+    let res = stream(&store, &uri, &host_secret, None);
+    assert_eq!(events(&res).len(), 1);
+}
+
+#[test]
+fn quiet_streams_send_keepalives() {
+    let store = MemoryStore::new();
+    let (session_name, _) = session_with_join(&store);
+
+    // Dave hasn't heard anything back yet
+    let res = post(&store, &format!("/sessions/{}/clients", encode(&session_name)), json!({ "client_name": "Dave", "rtc_offer": "offer" }));
+    let body = json_body(&res);
+    let client_secret = body["client_secret"].as_str().unwrap();
+
+    let uri = format!("/sessions/{}/clients/Dave/messages", encode(&session_name));
+    let res = stream(&store, &uri, client_secret, None);
+    assert_eq!(res.status(), 200);
+    assert!(events(&res).is_empty());
+    assert!(body_text(&res).contains(": keepalive\n\n"));
+}
+
+#[test]
+fn streams_need_the_header_once_query_secrets_are_off() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = session_with_join(&store);
+    let config = Config { allow_query_secrets: false, ..Config::default() };
+
+    // What EventSource would send, with no way to add an Authorization header
+    let uri = format!("/sessions/{}/messages?host_secret={}", encode(&session_name), encode(&host_secret));
+    let mut req = request("GET", &uri, None);
+    req.headers_mut().insert("Accept", "text/event-stream".parse().unwrap());
+    let res = send(&store, &config, req);
+    assert_eq!(res.status(), 400);
+    assert_eq!(json_body(&res)["error"]["code"], "secret_in_query");
+
+    // fetch can set one, and gets the stream
+    let uri = format!("/sessions/{}/messages", encode(&session_name));
+    let mut req = bearer(request("GET", &uri, None), &host_secret);
+    req.headers_mut().insert("Accept", "text/event-stream".parse().unwrap());
+    let res = send(&store, &config, req);
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["Content-Type"], "text/event-stream");
+    assert_eq!(events(&res)[0].1["type"], "start_join");
+}
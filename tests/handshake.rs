@@ -0,0 +1,889 @@
+//! Hosting, joining and the handshake between them, end to end
+
+mod common;
+
+use common::{bearer, get, get_as, host, host_with, join, json_body, messages, post, request, send};
+use rust_signalling::{config::Config, memory_store::MemoryStore, message::SignalMessage, store::SignalingStore};
+use serde_json::{json, Value};
+use spin_sdk::http::Response;
+use urlencoding::encode;
+
+fn host_messages(store: &MemoryStore, session_name: &str, host_secret: &str) -> Response {
+    get_as(store, &format!("/host/messages?session_name={}", encode(session_name)), host_secret)
+}
+
+fn host_messages_since(store: &MemoryStore, session_name: &str, host_secret: &str, since: &str) -> Response {
+    get_as(store, &format!(
+        "/host/messages?session_name={}&since={}",
+        encode(session_name),
+        encode(since),
+    ), host_secret)
+}
+
+fn client_messages(store: &MemoryStore, session_name: &str, client_name: &str, client_secret: &str) -> Response {
+    get_as(store, &format!(
+        "/join/messages?session_name={}&client_name={}",
+        encode(session_name),
+        encode(client_name),
+    ), client_secret)
+}
+
+#[test]
+fn full_handshake() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, false);
+
+    // Bob asks to join with an offer
+    let res = post(&store, "/join", json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "rtc_offer": "bob's offer",
+    }));
+    assert_eq!(res.status(), 200);
+    let client_secret = json_body(&res)["client_secret"].as_str().unwrap().to_string();
+
+    // Alice lets him in
+    let res = post(&store, "/host/decision", json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "host_secret": host_secret,
+        "accept": true,
+    }));
+    assert_eq!(res.status(), 200);
+
+    // Bob trickles in some candidates
+    let res = post(&store, "/join/candidates", json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "client_secret": client_secret,
+        "candidates": ["candidate:bob 1", "candidate:bob 2"],
+    }));
+    assert_eq!(res.status(), 200);
+
+    // Alice sees the join request and the candidates
+    let res = host_messages(&store, &session_name, &host_secret);
+    assert_eq!(res.status(), 200);
+    let cursor = json_body(&res)["cursor"].as_str().unwrap().to_string();
+    let received = messages(&res);
+    assert_eq!(received.len(), 2);
+
+    // In the order they were sent
+    let start_join = &received[0];
+    assert_eq!(start_join["type"], "start_join");
+    assert_eq!(start_join["client_name"], "Bob");
+    assert_eq!(start_join["client_offer"], "bob's offer");
+
+    let ice = &received[1];
+    assert_eq!(ice["type"], "ice_candidate");
+    assert_eq!(ice["candidates"], json!(["candidate:bob 1", "candidate:bob 2"]));
+
+    // Alice answers
+    let res = post(&store, "/join/response", json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "host_secret": host_secret,
+        "messages": { "type": "answer", "answer": { "type": "answer", "sdp": "alice's answer" } },
+    }));
+    assert_eq!(res.status(), 200);
+
+    // And Bob gets it
+    let res = client_messages(&store, &session_name, "Bob", &client_secret);
+    assert_eq!(res.status(), 200);
+    let received = messages(&res);
+    assert_eq!(received, vec![
+        json!({ "type": "join_accepted" }),
+        json!({ "type": "answer", "answer": { "type": "answer", "sdp": "alice's answer" } }),
+    ]);
+
+    // Polling from the cursor acknowledges everything before it
+    let res = host_messages_since(&store, &session_name, &host_secret, &cursor);
+    assert!(messages(&res).is_empty());
+    assert_eq!(json_body(&res)["cursor"], json!(cursor));
+
+    let res = host_messages(&store, &session_name, &host_secret);
+    assert!(messages(&res).is_empty());
+}
+
+#[test]
+fn messages_stay_until_acknowledged() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, false);
+
+    for client_name in ["Bob", "Carol", "Dave"] {
+        post(&store, "/join", json!({
+            "session_name": session_name,
+            "client_name": client_name,
+            "rtc_offer": "offer",
+        }));
+    }
+
+    // Losing a response doesn't lose the messages
+    let first = json_body(&host_messages(&store, &session_name, &host_secret));
+    let again = json_body(&host_messages(&store, &session_name, &host_secret));
+    assert_eq!(first["messages"], again["messages"]);
+
+    let ids: Vec<&str> = first["messages"].as_array().unwrap().iter()
+        .map(|message| message["id"].as_str().unwrap())
+        .collect();
+    assert_eq!(ids.len(), 3);
+
+    // Explicitly acknowledge the first two
+    let res = post(&store, "/host/messages/ack", json!({
+        "session_name": session_name,
+        "host_secret": host_secret,
+        "cursor": ids[1],
+    }));
+    assert_eq!(res.status(), 200);
+
+    let res = host_messages(&store, &session_name, &host_secret);
+    let received = messages(&res);
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0]["client_name"], "Dave");
+
+    // Acknowledging needs the secret
+    let res = post(&store, "/host/messages/ack", json!({
+        "session_name": session_name,
+        "host_secret": "not the secret",
+        "cursor": ids[2],
+    }));
+    assert_eq!(res.status(), 401);
+}
+
+#[test]
+fn polls_hint_when_to_poll_next() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, false);
+
+    // Nothing going on yet, so back off
+    let body = json_body(&host_messages(&store, &session_name, &host_secret));
+    assert_eq!(body["has_more"], false);
+    assert_eq!(body["next_poll_ms"], 3000);
+    assert!(body["server_time"].as_i64().unwrap() > 0);
+
+    // Someone's joining, so keep up
+    let client_secret = join(&store, &session_name, "Bob");
+    let body = json_body(&host_messages(&store, &session_name, &host_secret));
+    assert_eq!(body["next_poll_ms"], 250);
+
+    // Still mid-handshake, even once the host has the message
+    let uri = format!("/join/messages?session_name={}&client_name=Bob&wait=0", encode(&session_name));
+    let body = json_body(&get_as(&store, &uri, &client_secret));
+    assert_eq!(body["next_poll_ms"], 250);
+
+    // Until it goes quiet
+    store.advance_clock(120);
+    let body = json_body(&get_as(&store, &uri, &client_secret));
+    assert_eq!(body["next_poll_ms"], 3000);
+
+    // A wait has to be a number of seconds
+    let uri = format!("/host/messages?session_name={}&wait=soon", encode(&session_name));
+    assert_eq!(get_as(&store, &uri, &host_secret).status(), 400);
+}
+
+#[test]
+fn full_pages_say_there_is_more() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, false);
+
+    // More than a join rate limit would let through, so straight into the mailbox
+    for i in 0..150 {
+        let message = SignalMessage::ClientLeft { client_name: format!("Client {i}") };
+        store.push_message_to_host(&session_name, &message).unwrap();
+    }
+
+    let body = json_body(&host_messages(&store, &session_name, &host_secret));
+    assert_eq!(body["messages"].as_array().unwrap().len(), 100);
+    assert_eq!(body["has_more"], true);
+    assert_eq!(body["next_poll_ms"], 0);
+
+    let cursor = body["cursor"].as_str().unwrap();
+    let body = json_body(&host_messages_since(&store, &session_name, &host_secret, cursor));
+    assert_eq!(body["messages"].as_array().unwrap().len(), 50);
+    assert_eq!(body["has_more"], false);
+
+    // A page that's exactly full with nothing after it isn't more
+    for i in 0..100 {
+        let message = SignalMessage::ClientLeft { client_name: format!("Client {i}") };
+        store.push_message_to_host(&session_name, &message).unwrap();
+    }
+    let cursor = body["cursor"].as_str().unwrap();
+    let body = json_body(&host_messages_since(&store, &session_name, &host_secret, cursor));
+    assert_eq!(body["messages"].as_array().unwrap().len(), 100);
+    assert_eq!(body["has_more"], false);
+    assert_ne!(body["next_poll_ms"], 0);
+}
+
+#[test]
+fn wrong_secrets_are_rejected() {
+    let store = MemoryStore::new();
+    let (session_name, _) = host(&store, false);
+
+    let res = host_messages(&store, &session_name, "not the secret");
+    assert_eq!(res.status(), 401);
+
+    let res = client_messages(&store, &session_name, "Bob", "not the secret");
+    assert_eq!(res.status(), 401);
+}
+
+#[test]
+fn secrets_in_the_query_are_deprecated() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, false);
+
+    let uri = format!(
+        "/host/messages?session_name={}&host_secret={}",
+        encode(&session_name),
+        encode(&host_secret),
+    );
+
+    let res = get(&store, &uri);
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["Deprecation"], "true");
+    assert!(!get_as(&store, &format!("/host/messages?session_name={}", encode(&session_name)), &host_secret)
+        .headers()
+        .contains_key("Deprecation"));
+
+    // And refused outright once they're switched off, without the secret showing up anywhere
+    let config = Config { allow_query_secrets: false, ..Config::default() };
+    let res = send(&store, &config, request("GET", &uri, None));
+    assert_eq!(res.status(), 400);
+    assert_eq!(json_body(&res)["error"]["code"], "secret_in_query");
+    assert!(!String::from_utf8_lossy(res.body().as_ref().unwrap()).contains(&host_secret));
+
+    // POST bodies can use the header too
+    let req = request("POST", "/host/heartbeat", Some(json!({ "session_name": session_name })));
+    assert_eq!(send(&store, &config, bearer(req, &host_secret)).status(), 200);
+
+    // No secret at all is a 401 that says how to authenticate
+    let res = get(&store, &format!("/host/messages?session_name={}", encode(&session_name)));
+    assert_eq!(res.status(), 401);
+    assert_eq!(res.headers()["WWW-Authenticate"], "Bearer");
+}
+
+#[test]
+fn errors_say_what_went_wrong() {
+    let store = MemoryStore::new();
+
+    let error = |res: Response| (res.status().as_u16(), json_body(&res)["error"]["code"].clone());
+
+    assert_eq!(error(post(&store, "/host", json!({ "host_name": "Alice" }))), (400, json!("missing_parameter")));
+    assert_eq!(error(post(&store, "/host", json!({ "public": true, "host_name": "Alice", "max_clients": 0 }))), (400, json!("invalid_parameter")));
+    assert_eq!(error(send(&store, &Config::default(), request("POST", "/join", None))), (400, json!("missing_parameter")));
+    let mut req = request("POST", "/join", None);
+    *req.body_mut() = Some("{ not json".into());
+    assert_eq!(error(send(&store, &Config::default(), req)), (400, json!("invalid_body")));
+    assert_eq!(error(get(&store, "/nowhere")), (404, json!("not_found")));
+    assert_eq!(error(post(&store, "/join", json!({
+        "session_name": "nobody-home",
+        "client_name": "Bob",
+        "rtc_offer": "offer",
+    }))), (404, json!("no_such_session")));
+
+    let res = get(&store, "/host/messages?session_name=nobody-home&host_secret=guess");
+    assert_eq!(res.status(), 401);
+    assert_eq!(json_body(&res), json!({
+        "success": false,
+        "error": { "code": "unauthenticated", "message": "Not authenticated" },
+    }));
+}
+
+#[test]
+fn duplicate_client_names_are_rejected() {
+    let store = MemoryStore::new();
+    let (session_name, _) = host(&store, false);
+
+    let join = json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "rtc_offer": "offer",
+    });
+
+    assert_eq!(post(&store, "/join", join.clone()).status(), 200);
+
+    let res = post(&store, "/join", join);
+    assert_eq!(res.status(), 409);
+    assert_eq!(json_body(&res)["error"]["code"], "name_taken");
+}
+
+#[test]
+fn expired_sessions_stop_authenticating() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, false);
+
+    assert_eq!(host_messages(&store, &session_name, &host_secret).status(), 200);
+
+    store.advance_clock(3600);
+    assert_eq!(host_messages(&store, &session_name, &host_secret).status(), 401);
+}
+
+#[test]
+fn heartbeats_keep_the_session_and_its_clients_alive() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, true);
+
+    let res = post(&store, "/join", json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "rtc_offer": "offer",
+    }));
+    let client_secret = json_body(&res)["client_secret"].as_str().unwrap().to_string();
+
+    // Well past the original 600 seconds, as long as the host checks in every so often
+    for _ in 0..4 {
+        store.advance_clock(300);
+
+        let res = post(&store, "/host/heartbeat", json!({
+            "session_name": session_name,
+            "host_secret": host_secret,
+        }));
+        assert_eq!(res.status(), 200);
+        assert!(json_body(&res)["expires_at"].as_i64().is_some());
+    }
+
+    assert_eq!(host_messages(&store, &session_name, &host_secret).status(), 200);
+    assert_eq!(client_messages(&store, &session_name, "Bob", &client_secret).status(), 200);
+    let body = json_body(&get(&store, "/sessions"));
+    assert_eq!(body["sessions"][0]["session_name"], json!(session_name));
+
+    // Heartbeats need the secret
+    let res = post(&store, "/host/heartbeat", json!({
+        "session_name": session_name,
+        "host_secret": "not the secret",
+    }));
+    assert_eq!(res.status(), 401);
+}
+
+#[test]
+fn host_polls_renew_the_session() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, false);
+
+    let first = json_body(&host_messages(&store, &session_name, &host_secret))["expires_at"].as_i64().unwrap();
+
+    for _ in 0..4 {
+        store.advance_clock(300);
+        assert_eq!(host_messages(&store, &session_name, &host_secret).status(), 200);
+    }
+
+    let last = json_body(&host_messages(&store, &session_name, &host_secret))["expires_at"].as_i64().unwrap();
+    assert_eq!(last - first, 1200);
+
+    // But it's gone once the host stops
+    store.advance_clock(3600);
+    let res = post(&store, "/host/heartbeat", json!({
+        "session_name": session_name,
+        "host_secret": host_secret,
+    }));
+    assert_eq!(res.status(), 401);
+}
+
+#[test]
+fn pending_clients_candidates_wait_for_the_host() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, false);
+    let bob_secret = join(&store, &session_name, "Bob");
+
+    let candidates = json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "client_secret": bob_secret,
+        "candidates": ["candidate:bob"],
+    });
+    assert_eq!(post(&store, "/join/candidates", candidates.clone()).status(), 200);
+    let received = messages(&host_messages(&store, &session_name, &host_secret));
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0]["type"], "start_join");
+
+    let decision = json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "host_secret": host_secret,
+        "accept": true,
+    });
+    assert_eq!(post(&store, "/host/decision", decision.clone()).status(), 200);
+    assert_eq!(post(&store, "/join/candidates", candidates).status(), 200);
+
+    // Once he's in, they follow his offer
+    let received = messages(&host_messages(&store, &session_name, &host_secret));
+    assert_eq!(received.len(), 3);
+    assert_eq!(received[1], json!({ "type": "ice_candidate", "client_name": "Bob", "candidates": ["candidate:bob"] }));
+
+    // Only pending joins can be decided on
+    assert_eq!(post(&store, "/host/decision", decision).status(), 404);
+}
+
+#[test]
+fn hosts_can_only_send_well_formed_messages() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, false);
+    let bob_secret = join(&store, &session_name, "Bob");
+
+    let respond = |messages: Value| post(&store, "/join/response", json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "host_secret": host_secret,
+        "messages": messages,
+    }));
+
+    // Missing fields, unknown types, and things only the server says are all turned away
+    assert_eq!(respond(json!({ "type": "answer" })).status(), 400);
+    assert_eq!(respond(json!({ "type": "hello" })).status(), 400);
+    assert_eq!(respond(json!({ "type": "kicked", "reason": "Forged" })).status(), 400);
+    assert_eq!(respond(json!({ "type": "ice_candidate", "candidates": "not a list" })).status(), 400);
+
+    // A bad message anywhere in a batch means none of it is sent
+    let res = respond(json!([
+        { "type": "ice_candidate", "candidates": ["candidate:alice"] },
+        { "type": "join_accepted" },
+    ]));
+    assert_eq!(res.status(), 400);
+    assert!(messages(&client_messages(&store, &session_name, "Bob", &bob_secret)).is_empty());
+
+    let res = respond(json!([
+        { "type": "ice_candidate", "candidates": ["candidate:alice"] },
+        { "type": "end_of_candidates", "ufrag": "abcd", "mid": "0" },
+    ]));
+    assert_eq!(res.status(), 200);
+
+    // Those wait for Alice's answer, which lets Bob in without her deciding first
+    assert!(messages(&client_messages(&store, &session_name, "Bob", &bob_secret)).is_empty());
+    let answer = json!({ "type": "answer", "answer": { "type": "answer", "sdp": "alice's answer" } });
+    assert_eq!(respond(answer.clone()).status(), 200);
+    assert_eq!(messages(&client_messages(&store, &session_name, "Bob", &bob_secret)), vec![
+        json!({ "type": "join_accepted" }),
+        answer,
+        json!({ "type": "ice_candidate", "candidates": ["candidate:alice"] }),
+        json!({ "type": "end_of_candidates", "ufrag": "abcd", "mid": "0" }),
+    ]);
+}
+
+#[test]
+fn rejected_clients_are_told_why() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, false);
+    let bob_secret = join(&store, &session_name, "Bob");
+
+    // Reasons are text
+    let res = post(&store, "/host/decision", json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "host_secret": host_secret,
+        "accept": false,
+        "reason": 42,
+    }));
+    assert_eq!(res.status(), 400);
+
+    let res = post(&store, "/host/decision", json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "host_secret": host_secret,
+        "accept": false,
+        "reason": "Lobby is private",
+    }));
+    assert_eq!(res.status(), 200);
+
+    let received = messages(&client_messages(&store, &session_name, "Bob", &bob_secret));
+    assert_eq!(received, vec![json!({ "type": "join_rejected", "reason": "Lobby is private" })]);
+
+    // Bob can't do anything else, and his secret goes away
+    let res = post(&store, "/join/candidates", json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "client_secret": bob_secret,
+        "candidates": ["candidate:bob"],
+    }));
+    assert_eq!(res.status(), 403);
+
+    store.advance_clock(120);
+    assert_eq!(client_messages(&store, &session_name, "Bob", &bob_secret).status(), 401);
+}
+
+#[test]
+fn full_sessions_turn_clients_away() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host_with(&store, json!({
+        "public": true,
+        "host_name": "Alice",
+        "max_clients": 1,
+    }));
+    join(&store, &session_name, "Bob");
+
+    let res = post(&store, "/join", json!({
+        "session_name": session_name,
+        "client_name": "Carol",
+        "rtc_offer": "offer",
+    }));
+    assert_eq!(res.status(), 409);
+    assert_eq!(json_body(&res)["error"]["code"], "session_full");
+
+    // Both the host and the listing can see how full it is
+    let occupancy = json!({ "clients": 1, "waiting": 0, "max_clients": 1 });
+    assert_eq!(json_body(&host_messages(&store, &session_name, &host_secret))["occupancy"], occupancy);
+    assert_eq!(json_body(&get(&store, "/sessions"))["sessions"][0]["occupancy"], occupancy);
+}
+
+#[test]
+fn waiting_clients_are_promoted_in_order() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host_with(&store, json!({
+        "public": false,
+        "host_name": "Alice",
+        "max_clients": 1,
+        "waiting_list": true,
+    }));
+    let bob_secret = join(&store, &session_name, "Bob");
+
+    // Carol and Dave have to wait their turn
+    let mut waiting = Vec::new();
+    for client_name in ["Carol", "Dave"] {
+        let res = post(&store, "/join", json!({
+            "session_name": session_name,
+            "client_name": client_name,
+            "rtc_offer": format!("{client_name}'s offer"),
+        }));
+        let body = json_body(&res);
+        assert_eq!(body["waiting"], true);
+        waiting.push(body["client_secret"].as_str().unwrap().to_string());
+    }
+
+    let res = host_messages(&store, &session_name, &host_secret);
+    let cursor = json_body(&res)["cursor"].as_str().unwrap().to_string();
+    assert_eq!(messages(&res).len(), 1);
+    assert_eq!(json_body(&res)["occupancy"], json!({ "clients": 1, "waiting": 2, "max_clients": 1 }));
+
+    let received = messages(&client_messages(&store, &session_name, "Dave", &waiting[1]));
+    assert_eq!(received, vec![json!({ "type": "waiting", "position": 2 })]);
+
+    // Alice hasn't had their offers, so there's nothing to answer yet
+    let res = post(&store, "/join/response", json!({
+        "session_name": session_name,
+        "client_name": "Carol",
+        "host_secret": host_secret,
+        "messages": { "type": "answer", "answer": { "type": "answer", "sdp": "alice's answer" } },
+    }));
+    assert_eq!(res.status(), 403);
+
+    // Bob leaving lets Carol through
+    post(&store, "/join/leave", json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "client_secret": bob_secret,
+    }));
+
+    let received = messages(&host_messages_since(&store, &session_name, &host_secret, &cursor));
+    assert_eq!(received[0]["type"], "client_left");
+    assert_eq!(received[1]["type"], "start_join");
+    assert_eq!(received[1]["client_name"], "Carol");
+    assert_eq!(received[1]["client_offer"], "Carol's offer");
+    assert_eq!(received.len(), 2);
+
+    // And turning Carol away lets Dave through
+    let res = post(&store, "/host/decision", json!({
+        "session_name": session_name,
+        "client_name": "Carol",
+        "host_secret": host_secret,
+        "accept": false,
+    }));
+    assert_eq!(res.status(), 200);
+
+    let received = messages(&client_messages(&store, &session_name, "Dave", &waiting[1]));
+    assert_eq!(received.last().unwrap()["type"], "promoted");
+}
+
+#[test]
+fn password_protected_sessions_need_the_password() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host_with(&store, json!({
+        "public": false,
+        "host_name": "Alice",
+        "password": "hunter2",
+    }));
+
+    for password in [json!(null), json!("hunter3")] {
+        let res = post(&store, "/join", json!({
+            "session_name": session_name,
+            "client_name": "Mallory",
+            "rtc_offer": "offer",
+            "password": password,
+        }));
+        assert_eq!(res.status(), 403);
+    }
+
+    // Nothing reached the host
+    assert!(messages(&host_messages(&store, &session_name, &host_secret)).is_empty());
+
+    let res = post(&store, "/join", json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "rtc_offer": "offer",
+        "password": "hunter2",
+    }));
+    assert_eq!(res.status(), 200);
+}
+
+#[test]
+fn repeated_wrong_passwords_are_throttled() {
+    let store = MemoryStore::new();
+    let (session_name, _) = host_with(&store, json!({
+        "public": false,
+        "host_name": "Alice",
+        "password": "hunter2",
+    }));
+
+    let attempt_from = |address: &str, client_name: &str, password: &str| {
+        let mut req = request("POST", "/join", Some(json!({
+            "session_name": session_name,
+            "client_name": client_name,
+            "rtc_offer": "offer",
+            "password": password,
+        })));
+        req.headers_mut().insert("spin-client-addr", address.parse().unwrap());
+
+        send(&store, &Config::default(), req).status()
+    };
+
+    for _ in 0..5 {
+        assert_eq!(attempt_from("10.0.0.1:50000", "Mallory", "wrong"), 403);
+    }
+
+    // Even the right password has to wait now
+    assert_eq!(attempt_from("10.0.0.1:50000", "Mallory", "hunter2"), 429);
+
+    // But only from there, everyone else still gets in
+    assert_eq!(attempt_from("10.0.0.2:50000", "Bob", "hunter2"), 200);
+
+    store.advance_clock(60);
+    assert_eq!(attempt_from("10.0.0.1:50000", "Mallory", "hunter2"), 200);
+}
+
+#[test]
+fn kicked_clients_are_told_and_revoked() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, false);
+    let bob_secret = join(&store, &session_name, "Bob");
+
+    post(&store, "/host/decision", json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "host_secret": host_secret,
+        "accept": true,
+    }));
+
+    // Bob has read his mailbox up to here
+    let cursor = json_body(&client_messages(&store, &session_name, "Bob", &bob_secret))["cursor"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    post(&store, "/join/response", json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "host_secret": host_secret,
+        "messages": { "type": "answer", "answer": { "type": "answer", "sdp": "alice's answer" } },
+    }));
+
+    let res = post(&store, "/host/kick", json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "host_secret": host_secret,
+        "reason": "Being rude",
+    }));
+    assert_eq!(res.status(), 200);
+
+    // The answer he never read is gone, only the kick is left
+    let received = messages(&get(&store, &format!(
+        "/join/messages?session_name={}&client_name=Bob&client_secret={}&since={}",
+        encode(&session_name),
+        encode(&bob_secret),
+        encode(&cursor),
+    )));
+    assert_eq!(received, vec![json!({ "type": "kicked", "reason": "Being rude" })]);
+
+    store.advance_clock(120);
+    assert_eq!(client_messages(&store, &session_name, "Bob", &bob_secret).status(), 401);
+
+    // Not banned, so he can come back
+    join(&store, &session_name, "Bob");
+
+    // Kicking needs the secret and a client
+    let kick = json!({
+        "session_name": session_name,
+        "client_name": "Carol",
+        "host_secret": host_secret,
+    });
+    assert_eq!(post(&store, "/host/kick", kick).status(), 404);
+}
+
+#[test]
+fn leaving_after_being_kicked_changes_nothing() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host_with(&store, json!({
+        "public": false,
+        "host_name": "Alice",
+        "max_clients": 1,
+        "waiting_list": true,
+    }));
+    let bob_secret = join(&store, &session_name, "Bob");
+    join(&store, &session_name, "Carol");
+    let dave_secret = join(&store, &session_name, "Dave");
+
+    // Kicking Bob lets Carol through
+    let res = post(&store, "/host/kick", json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "host_secret": host_secret,
+    }));
+    assert_eq!(res.status(), 200);
+    let res = host_messages(&store, &session_name, &host_secret);
+    let cursor = json_body(&res)["cursor"].as_str().unwrap().to_string();
+
+    // Bob leaving while he reads why isn't news to Alice, and doesn't let Dave through too
+    let res = post(&store, "/join/leave", json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "client_secret": bob_secret,
+    }));
+    assert_eq!(res.status(), 200);
+
+    assert!(messages(&host_messages_since(&store, &session_name, &host_secret, &cursor)).is_empty());
+    let received = messages(&client_messages(&store, &session_name, "Dave", &dave_secret));
+    assert_eq!(received, vec![json!({ "type": "waiting", "position": 2 })]);
+}
+
+#[test]
+fn banned_clients_cannot_rejoin() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, false);
+
+    let join_from = |client_name: &str, address: &str| {
+        let mut req = request("POST", "/join", Some(json!({
+            "session_name": session_name,
+            "client_name": client_name,
+            "rtc_offer": "offer",
+        })));
+        req.headers_mut().insert("spin-client-addr", address.parse().unwrap());
+
+        send(&store, &Config::default(), req).status()
+    };
+
+    assert_eq!(join_from("Mallory", "10.0.0.1:50000"), 200);
+    assert_eq!(join_from("Eve", "10.0.0.2:50000"), 200);
+
+    for (client_name, ban_address) in [("Mallory", false), ("Eve", true)] {
+        let res = post(&store, "/host/kick", json!({
+            "session_name": session_name,
+            "client_name": client_name,
+            "host_secret": host_secret,
+            "ban": true,
+            "ban_address": ban_address,
+        }));
+        assert_eq!(res.status(), 200);
+    }
+
+    // Past the grace period, so the names would be free otherwise
+    store.advance_clock(120);
+
+    assert_eq!(join_from("Mallory", "10.0.0.3:50000"), 403);
+    assert_eq!(join_from("Eve", "10.0.0.4:50000"), 403);
+    assert_eq!(join_from("Eve2", "10.0.0.2:50001"), 403);
+    assert_eq!(join_from("Mallory2", "10.0.0.1:50001"), 200);
+}
+
+#[test]
+fn closing_a_session_tells_the_clients() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, true);
+    let bob_secret = join(&store, &session_name, "Bob");
+    let carol_secret = join(&store, &session_name, "Carol");
+
+    let res = send(&store, &Config::default(), request("DELETE", &format!(
+        "/host?session_name={}&host_secret={}",
+        encode(&session_name),
+        encode(&host_secret),
+    ), None));
+    assert_eq!(res.status(), 200);
+
+    // The host is done
+    assert_eq!(host_messages(&store, &session_name, &host_secret).status(), 401);
+    let body = json_body(&get(&store, "/sessions"));
+    assert!(body["sessions"].as_array().unwrap().is_empty());
+
+    // Clients can still get in long enough to find out
+    for (client_name, client_secret) in [("Bob", &bob_secret), ("Carol", &carol_secret)] {
+        let received = messages(&client_messages(&store, &session_name, client_name, client_secret));
+        assert_eq!(received.last().unwrap()["type"], "session_closed");
+    }
+
+    store.advance_clock(120);
+    assert_eq!(client_messages(&store, &session_name, "Bob", &bob_secret).status(), 401);
+}
+
+#[test]
+fn closing_a_session_needs_the_secret() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, false);
+
+    let res = send(&store, &Config::default(), request("DELETE", &format!(
+        "/host?session_name={}&host_secret=not%20the%20secret",
+        encode(&session_name),
+    ), None));
+    assert_eq!(res.status(), 401);
+
+    assert_eq!(host_messages(&store, &session_name, &host_secret).status(), 200);
+}
+
+#[test]
+fn leaving_a_session_tells_the_host() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, false);
+    let bob_secret = join(&store, &session_name, "Bob");
+
+    let leave = json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "client_secret": bob_secret,
+    });
+    assert_eq!(post(&store, "/join/leave", leave.clone()).status(), 200);
+
+    let received = messages(&host_messages(&store, &session_name, &host_secret));
+    assert_eq!(received.last().unwrap(), &json!({ "type": "client_left", "client_name": "Bob" }));
+
+    // Bob's secret is gone, and so is his name
+    assert_eq!(client_messages(&store, &session_name, "Bob", &bob_secret).status(), 401);
+    assert_eq!(post(&store, "/join/leave", leave).status(), 401);
+    join(&store, &session_name, "Bob");
+}
+
+#[test]
+fn public_sessions_are_listed_a_page_at_a_time() {
+    let store = MemoryStore::new();
+    for _ in 0..3 {
+        host(&store, true);
+    }
+    let (private_name, _) = host(&store, false);
+
+    let first = json_body(&get(&store, "/sessions?limit=2"));
+    let second = json_body(&get(&store, &format!(
+        "/sessions?limit=2&cursor={}",
+        encode(first["next_cursor"].as_str().unwrap()),
+    )));
+
+    let names: Vec<&Value> = first["sessions"].as_array().unwrap().iter()
+        .chain(second["sessions"].as_array().unwrap())
+        .map(|session| &session["session_name"])
+        .collect();
+
+    assert_eq!(names.len(), 3);
+    assert!(!names.contains(&&json!(private_name)));
+    assert!(second["next_cursor"].is_null());
+    assert_eq!(first["sessions"][0]["host_name"], "Alice");
+
+    // Nothing is old enough yet
+    let body = json_body(&get(&store, "/sessions?min_age=60"));
+    assert!(body["sessions"].as_array().unwrap().is_empty());
+
+    // And everything goes away with the sessions themselves
+    store.advance_clock(3600);
+    let body = json_body(&get(&store, "/sessions"));
+    assert!(body["sessions"].as_array().unwrap().is_empty());
+}
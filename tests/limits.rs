@@ -0,0 +1,249 @@
+//! Payload limits and mailbox caps
+
+mod common;
+
+use common::{assert_error, json_body, request};
+use rust_signalling::{
+    config::{Config, MailboxOverflow},
+    memory_store::MemoryStore,
+    store::SignalingStore,
+};
+use serde_json::{json, Value};
+use spin_sdk::http::Response;
+use urlencoding::encode;
+
+struct Server {
+    config: Config,
+    store: MemoryStore,
+}
+
+impl Server {
+    fn new(config: Config) -> Self {
+        let store = MemoryStore::with_config(&config);
+        Self { config, store }
+    }
+
+    fn send(&self, method: &str, uri: &str, body: Option<Value>) -> Response {
+        common::send(&self.store, &self.config, request(method, uri, body))
+    }
+
+    /// Starts a session with Bob let in, returning its name, the host's secret and Bob's
+    fn session_with_bob(&self) -> (String, String, String) {
+        let body = json_body(&self.send("POST", "/host", Some(json!({ "public": false, "host_name": "Alice" }))));
+        let session_name = body["session_name"].as_str().unwrap().to_string();
+        let host_secret = body["host_secret"].as_str().unwrap().to_string();
+
+        let res = self.send("POST", "/join", Some(json!({
+            "session_name": session_name,
+            "client_name": "Bob",
+            "rtc_offer": "offer",
+        })));
+        let client_secret = json_body(&res)["client_secret"].as_str().unwrap().to_string();
+
+        let res = self.send("POST", "/host/decision", Some(json!({
+            "session_name": session_name,
+            "client_name": "Bob",
+            "host_secret": host_secret,
+            "accept": true,
+        })));
+        assert_eq!(res.status(), 200);
+
+        (session_name, host_secret, client_secret)
+    }
+
+    fn send_candidates(&self, session_name: &str, client_secret: &str, candidates: Value) -> Response {
+        self.send_candidates_as(session_name, "Bob", client_secret, candidates)
+    }
+
+    fn send_candidates_as(&self, session_name: &str, client_name: &str, client_secret: &str, candidates: Value) -> Response {
+        self.send("POST", "/join/candidates", Some(json!({
+            "session_name": session_name,
+            "client_name": client_name,
+            "client_secret": client_secret,
+            "candidates": candidates,
+        })))
+    }
+
+    /// Types of the messages waiting for the host
+    fn host_message_types(&self, session_name: &str, host_secret: &str) -> Vec<String> {
+        let res = self.send("GET", &format!(
+            "/host/messages?session_name={}&host_secret={}&wait=0",
+            encode(session_name),
+            encode(host_secret),
+        ), None);
+
+        json_body(&res)["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["message"]["type"].as_str().unwrap().to_string())
+            .collect()
+    }
+}
+
+#[test]
+fn oversized_payloads_are_turned_away() {
+    let server = Server::new(Config {
+        max_sdp_bytes: 1024,
+        max_candidates_per_message: 2,
+        max_candidate_bytes: 64,
+        ..Config::default()
+    });
+    let (session_name, host_secret, client_secret) = server.session_with_bob();
+
+    let res = server.send("POST", "/join", Some(json!({
+        "session_name": session_name,
+        "client_name": "Carol",
+        "rtc_offer": "a".repeat(1025),
+    })));
+    assert_error(&res, 413, "payload_too_large");
+
+    let res = server.send_candidates(&session_name, &client_secret, json!(["candidate:1", "candidate:2", "candidate:3"]));
+    assert_error(&res, 413, "payload_too_large");
+    let res = server.send_candidates(&session_name, &client_secret, json!([format!("candidate:{}", "c".repeat(55))]));
+    assert_error(&res, 413, "payload_too_large");
+    assert_eq!(server.send_candidates(&session_name, &client_secret, json!(["candidate:1", "candidate:2"])).status(), 200);
+
+    let res = server.send("POST", "/join/response", Some(json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "host_secret": host_secret,
+        "messages": { "type": "answer", "answer": { "type": "answer", "sdp": "a".repeat(1025) } },
+    })));
+    assert_error(&res, 413, "payload_too_large");
+}
+
+#[test]
+fn full_mailboxes_reject_new_messages() {
+    let server = Server::new(Config { max_mailbox_length: 4, ..Config::default() });
+    let (session_name, host_secret, client_secret) = server.session_with_bob();
+
+    // Bob's offer is already waiting and one place is kept for notifications, so there's room for two more
+    for _ in 0..2 {
+        assert_eq!(server.send_candidates(&session_name, &client_secret, json!(["candidate:1"])).status(), 200);
+    }
+
+    let res = server.send_candidates(&session_name, &client_secret, json!(["candidate:1"]));
+    assert_error(&res, 429, "mailbox_full");
+    assert!(res.headers().contains_key("Retry-After"));
+    assert_eq!(server.host_message_types(&session_name, &host_secret), ["start_join", "ice_candidate", "ice_candidate"]);
+}
+
+#[test]
+fn full_mailboxes_can_drop_the_oldest_instead() {
+    let server = Server::new(Config {
+        max_mailbox_length: 3,
+        mailbox_overflow: MailboxOverflow::DropOldest,
+        ..Config::default()
+    });
+    let (session_name, host_secret, client_secret) = server.session_with_bob();
+
+    for _ in 0..2 {
+        let res = server.send_candidates(&session_name, &client_secret, json!(["candidate:1"]));
+        assert_eq!(json_body(&res)["dropped"], 0);
+    }
+
+    let res = server.send_candidates(&session_name, &client_secret, json!(["candidate:1"]));
+    assert_eq!(res.status(), 200);
+    assert_eq!(json_body(&res)["dropped"], 1);
+    assert_eq!(server.host_message_types(&session_name, &host_secret), ["ice_candidate", "ice_candidate", "ice_candidate"]);
+}
+
+#[test]
+fn notifications_get_through_a_full_mailbox() {
+    let server = Server::new(Config { max_mailbox_length: 3, ..Config::default() });
+    let (session_name, host_secret, client_secret) = server.session_with_bob();
+
+    assert_eq!(server.send_candidates(&session_name, &client_secret, json!(["candidate:1"])).status(), 200);
+    assert_eq!(server.send_candidates(&session_name, &client_secret, json!(["candidate:1"])).status(), 429);
+
+    let res = server.send("POST", "/join/leave", Some(json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+        "client_secret": client_secret,
+    })));
+    assert_eq!(res.status(), 200);
+    assert_eq!(server.host_message_types(&session_name, &host_secret), ["start_join", "ice_candidate", "client_left"]);
+}
+
+#[test]
+fn joins_the_host_cant_hear_about_dont_hold_the_name() {
+    let server = Server::new(Config { max_mailbox_length: 1, ..Config::default() });
+    let (session_name, _, _) = server.session_with_bob();
+
+    // Bob's offer fills the host's mailbox
+    let res = server.send("POST", "/join", Some(json!({
+        "session_name": session_name,
+        "client_name": "Carol",
+        "rtc_offer": "offer",
+    })));
+    assert_error(&res, 429, "mailbox_full");
+    assert!(!server.store.session_has_client(&session_name, "Carol").unwrap());
+}
+
+#[test]
+fn notifications_dont_push_out_other_joins() {
+    let server = Server::new(Config { max_mailbox_length: 10, ..Config::default() });
+    let body = json_body(&server.send("POST", "/host", Some(json!({ "public": false, "host_name": "Alice" }))));
+    let session_name = body["session_name"].as_str().unwrap();
+    let host_secret = body["host_secret"].as_str().unwrap();
+
+    // Bob's join is the oldest thing the host hasn't read
+    let join = |client_name: &str| {
+        let res = server.send("POST", "/join", Some(json!({
+            "session_name": session_name,
+            "client_name": client_name,
+            "rtc_offer": "offer",
+        })));
+        json_body(&res)["client_secret"].as_str().unwrap().to_string()
+    };
+    join("Bob");
+    let carol_secret = join("Carol");
+    let res = server.send("POST", "/host/decision", Some(json!({
+        "session_name": session_name,
+        "client_name": "Carol",
+        "host_secret": host_secret,
+        "accept": true,
+    })));
+    assert_eq!(res.status(), 200);
+
+    // Carol fills the mailbox as far as she can
+    for _ in 0..7 {
+        assert_eq!(server.send_candidates_as(session_name, "Carol", &carol_secret, json!(["candidate:1"])).status(), 200);
+    }
+    let res = server.send_candidates_as(session_name, "Carol", &carol_secret, json!(["candidate:1"]));
+    assert_error(&res, 429, "mailbox_full");
+
+    // Her leaving still gets through, and Bob's join is still there for the host
+    let res = server.send("POST", "/join/leave", Some(json!({
+        "session_name": session_name,
+        "client_name": "Carol",
+        "client_secret": carol_secret,
+    })));
+    assert_eq!(res.status(), 200);
+
+    let types = server.host_message_types(session_name, host_secret);
+    assert_eq!(types.len(), 10);
+    assert_eq!(types[..2], ["start_join", "start_join"]);
+    assert_eq!(types[9], "client_left");
+}
+
+#[test]
+fn names_count_towards_the_message_they_end_up_in() {
+    let server = Server::new(Config { max_sdp_bytes: 1024, max_message_bytes: 1024, ..Config::default() });
+    let (session_name, _, _) = server.session_with_bob();
+
+    let res = server.send("POST", "/host", Some(json!({ "public": false, "host_name": "a".repeat(65) })));
+    assert_error(&res, 400, "invalid_parameter");
+
+    let join = |client_name: &str, rtc_offer: &str| server.send("POST", "/join", Some(json!({
+        "session_name": session_name,
+        "client_name": client_name,
+        "rtc_offer": rtc_offer,
+    })));
+    assert_error(&join(&"c".repeat(65), "offer"), 400, "invalid_parameter");
+
+    // The offer fits on its own, but not in a start_join along with everything else
+    assert_error(&join(&"c".repeat(64), &"o".repeat(1000)), 413, "payload_too_large");
+    assert_eq!(join("Carol", "offer").status(), 200);
+}
@@ -0,0 +1,120 @@
+//! Per-address and per-session request budgets
+
+mod common;
+
+use common::{json_body, request};
+use rust_signalling::{config::Config, memory_store::MemoryStore};
+use serde_json::{json, Value};
+use spin_sdk::http::Response;
+
+fn send(store: &MemoryStore, config: &Config, address: &str, uri: &str, body: Option<Value>) -> Response {
+    let method = if body.is_some() { "POST" } else { "GET" };
+    let mut req = request(method, uri, body);
+    req.headers_mut().insert("spin-client-addr", address.parse().unwrap());
+
+    common::send(store, config, req)
+}
+
+fn host(store: &MemoryStore, config: &Config, address: &str) -> Response {
+    send(store, config, address, "/sessions", Some(json!({ "public": true, "host_name": "Alice" })))
+}
+
+fn join(store: &MemoryStore, config: &Config, address: &str, session_name: &str, client_name: &str) -> Response {
+    send(store, config, address, "/join", Some(json!({
+        "session_name": session_name,
+        "client_name": client_name,
+        "rtc_offer": "offer",
+    })))
+}
+
+fn assert_limited(res: &Response, window_seconds: i64) {
+    assert_eq!(res.status(), 429);
+    assert_eq!(json_body(res)["error"]["code"], "too_many_requests");
+
+    let retry_after: i64 = res.headers()["Retry-After"].to_str().unwrap().parse().unwrap();
+    assert!((1..=2 * window_seconds).contains(&retry_after), "Retry-After: {retry_after}");
+}
+
+#[test]
+fn addresses_can_only_start_so_many_sessions() {
+    let config = Config { rate_limit_sessions_per_ip: 2, ..Config::default() };
+    let store = MemoryStore::with_config(&config);
+
+    assert_eq!(host(&store, &config, "10.0.0.1:5000").status(), 200);
+    assert_eq!(host(&store, &config, "10.0.0.1:5001").status(), 200);
+    assert_limited(&host(&store, &config, "10.0.0.1:5002"), 60);
+
+    // Someone else isn't held up by it
+    assert_eq!(host(&store, &config, "10.0.0.2:5000").status(), 200);
+
+    // And the budget comes back once the window has slid past
+    store.advance_clock(120);
+    assert_eq!(host(&store, &config, "10.0.0.1:5003").status(), 200);
+}
+
+#[test]
+fn sessions_can_only_take_so_many_joins() {
+    let config = Config { rate_limit_joins_per_session: 3, ..Config::default() };
+    let store = MemoryStore::with_config(&config);
+
+    let session_name = json_body(&host(&store, &config, "10.0.0.1:5000"))["session_name"].as_str().unwrap().to_string();
+
+    // Spread over addresses, it's still the one host's queue filling up
+    for i in 0..3 {
+        let res = join(&store, &config, &format!("10.0.1.{i}:5000"), &session_name, &format!("Client {i}"));
+        assert_eq!(res.status(), 200);
+    }
+    assert_limited(&join(&store, &config, "10.0.1.9:5000", &session_name, "Mallory"), 60);
+}
+
+#[test]
+fn polls_are_limited_per_client() {
+    let config = Config { rate_limit_requests_per_client: 3, ..Config::default() };
+    let store = MemoryStore::with_config(&config);
+
+    let body = json_body(&host(&store, &config, "10.0.0.1:5000"));
+    let session_name = urlencoding::encode(body["session_name"].as_str().unwrap()).into_owned();
+    let host_secret = body["host_secret"].as_str().unwrap();
+    let uri = format!("/host/messages?session_name={session_name}&host_secret={host_secret}");
+
+    for _ in 0..3 {
+        assert_eq!(send(&store, &config, "10.0.0.1:5000", &uri, None).status(), 200);
+    }
+    // From anywhere, since it's the session being polled too often
+    assert_limited(&send(&store, &config, "10.0.0.9:5000", &uri, None), 60);
+
+    // The address itself still has plenty left
+    assert_eq!(send(&store, &config, "10.0.0.1:5000", "/sessions", None).status(), 200);
+}
+
+#[test]
+fn limits_can_be_turned_off() {
+    let config = Config { rate_limit_sessions_per_ip: 0, ..Config::default() };
+    let store = MemoryStore::with_config(&config);
+
+    for _ in 0..20 {
+        assert_eq!(host(&store, &config, "10.0.0.1:5000").status(), 200);
+    }
+}
+
+#[test]
+fn the_address_can_come_from_a_proxy() {
+    let config = Config {
+        client_ip_header: "X-Forwarded-For".into(),
+        rate_limit_sessions_per_ip: 1,
+        ..Config::default()
+    };
+    let store = MemoryStore::with_config(&config);
+
+    let host_via_proxy = |forwarded_for: &str| {
+        let mut req = request("POST", "/sessions", Some(json!({ "public": false, "host_name": "Alice" })));
+        req.headers_mut().insert("spin-client-addr", "10.0.0.1:5000".parse().unwrap());
+        req.headers_mut().insert("X-Forwarded-For", forwarded_for.parse().unwrap());
+
+        common::send(&store, &config, req).status()
+    };
+
+    assert_eq!(host_via_proxy("203.0.113.1, 10.0.0.1"), 200);
+    assert_eq!(host_via_proxy("203.0.113.2, 10.0.0.1"), 200);
+    assert_eq!(host_via_proxy("203.0.113.1"), 429);
+}
@@ -0,0 +1,174 @@
+mod common;
+
+use common::{bearer, get_as, host, join, json_body, messages, request};
+use http::Method;
+use rust_signalling::{
+    config::Config,
+    error::{ApiError, ApiResult},
+    memory_store::MemoryStore,
+    router::{Context, Params, Router},
+    store::SignalingStore,
+};
+use serde_json::{json, Value};
+use spin_sdk::http::{Request, Response};
+use urlencoding::encode;
+
+fn send(store: &MemoryStore, method: &str, uri: &str, body: Option<Value>) -> Response {
+    common::send(store, &Config::default(), request(method, uri, body))
+}
+
+/// No body, with the secret in the Authorization header
+fn send_as(store: &MemoryStore, method: &str, uri: &str, secret: &str) -> Response {
+    common::send(store, &Config::default(), bearer(request(method, uri, None), secret))
+}
+
+#[test]
+fn sessions_can_be_driven_by_path() {
+    let store = MemoryStore::new();
+
+    let res = send(&store, "POST", "/sessions", Some(json!({ "public": false, "host_name": "Alice" })));
+    assert_eq!(res.status(), 200);
+    let body = json_body(&res);
+    // Session names have spaces in them
+    let session_name = encode(body["session_name"].as_str().unwrap()).into_owned();
+    let host_secret = body["host_secret"].as_str().unwrap();
+
+    let res = send(&store, "POST", &format!("/sessions/{session_name}/clients"), Some(json!({
+        "client_name": "Bob Smith",
+        "rtc_offer": "bob's offer",
+    })));
+    assert_eq!(res.status(), 200);
+    let client_secret = json_body(&res)["client_secret"].as_str().unwrap().to_string();
+
+    // Names are url-decoded on the way in
+    let res = send(&store, "POST", &format!("/sessions/{session_name}/clients/Bob%20Smith/decision"), Some(json!({
+        "host_secret": host_secret,
+        "accept": true,
+    })));
+    assert_eq!(res.status(), 200);
+
+    let res = send(&store, "GET", &format!("/sessions/{session_name}/messages?host_secret={}", encode(host_secret)), None);
+    assert_eq!(res.status(), 200);
+    assert_eq!(json_body(&res)["messages"][0]["message"]["client_name"], "Bob Smith");
+
+    let res = send(&store, "GET", &format!(
+        "/sessions/{session_name}/clients/Bob%20Smith/messages?client_secret={}",
+        encode(&client_secret)
+    ), None);
+    assert_eq!(json_body(&res)["messages"][0]["message"], json!({ "type": "join_accepted" }));
+
+    // The path wins over whatever's in the body
+    let res = send(&store, "POST", &format!("/sessions/{session_name}/heartbeat"), Some(json!({
+        "session_name": "somewhere-else",
+        "host_secret": host_secret,
+    })));
+    assert_eq!(res.status(), 200);
+}
+
+#[test]
+fn wrong_methods_are_told_what_is_allowed() {
+    let store = MemoryStore::new();
+
+    let res = send(&store, "PUT", "/sessions", None);
+    assert_eq!(res.status(), 405);
+    assert_eq!(res.headers()["Allow"], "POST, GET, HEAD, OPTIONS");
+    assert_eq!(json_body(&res)["error"]["code"], "method_not_allowed");
+
+    let res = send(&store, "OPTIONS", "/sessions/some-session/messages", None);
+    assert_eq!(res.status(), 204);
+    assert_eq!(res.headers()["Allow"], "GET, HEAD, OPTIONS");
+
+    assert_eq!(send(&store, "GET", "/sessions/some-session/nowhere", None).status(), 404);
+}
+
+#[test]
+fn head_requests_get_headers_only() {
+    let store = MemoryStore::new();
+
+    let res = send(&store, "HEAD", "/sessions", None);
+    assert_eq!(res.status(), 200);
+    assert!(res.body().is_none());
+}
+
+#[test]
+fn head_on_a_mailbox_leaves_it_alone() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, false);
+    join(&store, &session_name, "Bob");
+
+    let uri = format!("/host/messages?session_name={}", encode(&session_name));
+    let res = get_as(&store, &format!("{uri}&wait=0"), &host_secret);
+    let cursor = json_body(&res)["cursor"].as_str().unwrap().to_string();
+
+    // Not a poll, so nothing's acknowledged, even with since
+    let res = send_as(&store, "HEAD", &format!("{uri}&since={}", encode(&cursor)), &host_secret);
+    assert_eq!(res.status(), 200);
+    assert!(res.body().is_none());
+    assert_eq!(messages(&get_as(&store, &format!("{uri}&wait=0"), &host_secret)).len(), 1);
+
+    // But it does still need the secret
+    assert_eq!(send_as(&store, "HEAD", &uri, "not the secret").status(), 401);
+}
+
+#[test]
+fn bodies_can_be_left_out() {
+    let store = MemoryStore::new();
+    let (session_name, host_secret) = host(&store, false);
+    let session_name = encode(&session_name).into_owned();
+
+    // Everything's in the path and the Authorization header
+    let res = send_as(&store, "POST", &format!("/sessions/{session_name}/heartbeat"), &host_secret);
+    assert_eq!(res.status(), 200);
+
+    // Bodies that are there still have to be JSON
+    let mut req = bearer(request("POST", &format!("/sessions/{session_name}/heartbeat"), None), &host_secret);
+    *req.body_mut() = Some("not json".into());
+    assert_eq!(common::send(&store, &Config::default(), req).status(), 400);
+}
+
+fn thing(_: &dyn SignalingStore, _: &Request, params: &Params) -> ApiResult<Response> {
+    http::Response::builder()
+        .status(200)
+        .body(Some(params["id"].clone().into()))
+        .map_err(ApiError::from)
+}
+
+fn members_only(ctx: &Context, next: &dyn Fn() -> ApiResult<Response>) -> ApiResult<Response> {
+    if ctx.params["id"] == "secret" {
+        return Err(ApiError::Unauthenticated);
+    }
+    next()
+}
+
+#[test]
+fn route_middleware_can_answer_early() {
+    let store = MemoryStore::new();
+    let config = Config::default();
+    let router = Router::new()
+        .route(Method::GET, "/things/{id}", thing).with(members_only)
+        .route(Method::GET, "/open/{id}", thing);
+
+    let res = router.handle(&store, &config, &request("GET", "/things/public", None)).unwrap();
+    assert_eq!(res.body().as_deref(), Some(b"public".as_slice()));
+
+    assert!(matches!(
+        router.handle(&store, &config, &request("GET", "/things/secret", None)),
+        Err(ApiError::Unauthenticated)
+    ));
+
+    // Only on the route it was added to
+    assert_eq!(router.handle(&store, &config, &request("GET", "/open/secret", None)).unwrap().status(), 200);
+}
+
+#[test]
+fn only_backend_failures_are_store_unavailable() {
+    use anyhow::{anyhow, Context as _};
+    use rust_signalling::error::StoreFailure;
+
+    let unreachable: anyhow::Result<()> = Err(StoreFailure::error("Command failed: GET"));
+    assert_eq!(ApiError::from(unreachable.context("Error retrieving session").unwrap_err()).code(), "store_unavailable");
+
+    // Getting back something we can't decode is on us, not the store
+    assert_eq!(ApiError::from(anyhow!("Error decoding client status")).code(), "internal_error");
+    assert_eq!(ApiError::from(anyhow::Error::from(ApiError::NoSuchSession)).code(), "no_such_session");
+}
@@ -0,0 +1,63 @@
+//! Store behavior, exercised through the in-memory store
+
+use rust_signalling::{config::Config, memory_store::MemoryStore, message::SignalMessage, store::{SessionOptions, SignalingStore}};
+
+#[test]
+fn session_names_cannot_be_registered_twice() {
+    let store = MemoryStore::new();
+
+    let first = store.register_session("quick brown fox", false, "Alice", &SessionOptions::default()).unwrap();
+    let second = store.register_session("quick brown fox", true, "Mallory", &SessionOptions::default()).unwrap();
+
+    assert!(first.is_some());
+    assert!(second.is_none());
+    assert_eq!(store.get_host_secret("quick brown fox").unwrap(), first);
+}
+
+#[test]
+fn expired_session_names_can_be_reused() {
+    let store = MemoryStore::new();
+
+    assert!(store.register_session("quick brown fox", false, "Alice", &SessionOptions::default()).unwrap().is_some());
+    store.advance_clock(3600);
+    assert!(store.register_session("quick brown fox", false, "Bob", &SessionOptions::default()).unwrap().is_some());
+}
+
+#[test]
+fn names_stay_retired_while_old_tokens_could_be_valid() {
+    let config = Config {
+        token_key: Some("a key that is at least thirty-two characters".into()),
+        token_ttl_seconds: 7200,
+        ..Config::default()
+    };
+    let store = MemoryStore::with_config(&config);
+    let options = SessionOptions::default();
+
+    assert!(store.create_session("quick brown fox", false, "Alice", &options).unwrap().is_some());
+
+    // The session's gone, but Alice's token would still check out
+    store.advance_clock(3600);
+    assert!(store.create_session("quick brown fox", false, "Mallory", &options).unwrap().is_none());
+
+    store.advance_clock(3600);
+    assert!(store.create_session("quick brown fox", false, "Bob", &options).unwrap().is_some());
+}
+
+#[test]
+fn messages_are_read_a_batch_at_a_time() {
+    let config = Config { message_batch_size: 10, ..Config::default() };
+    let store = MemoryStore::with_config(&config);
+    store.register_session("quick brown fox", false, "Alice", &SessionOptions::default()).unwrap();
+
+    for i in 0..15 {
+        let message = SignalMessage::ClientLeft { client_name: format!("Client {i}") };
+        store.push_message_to_host("quick brown fox", &message).unwrap();
+    }
+
+    let first = store.get_messages_for_host("quick brown fox", None, 0).unwrap();
+    assert_eq!(first.len(), 10);
+
+    let rest = store.get_messages_for_host("quick brown fox", Some(&first[9].id), 0).unwrap();
+    assert_eq!(rest.len(), 5);
+    assert_eq!(rest[0].message["client_name"], "Client 10");
+}
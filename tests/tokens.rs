@@ -0,0 +1,202 @@
+//! Signed tokens, with a token_key configured
+
+mod common;
+
+use common::{bearer, json_body, request};
+use rust_signalling::{
+    config::Config,
+    memory_store::MemoryStore,
+    store::{SessionOptions, SignalingStore},
+};
+use serde_json::{json, Value};
+use spin_sdk::http::Response;
+use urlencoding::encode;
+
+struct Server {
+    config: Config,
+    store: MemoryStore,
+}
+
+impl Server {
+    fn new() -> Self {
+        let config = Config {
+            token_key: Some("a key that is at least thirty-two characters".into()),
+            token_ttl_seconds: 3600,
+            ..Config::default()
+        };
+        let store = MemoryStore::with_config(&config);
+
+        Self { config, store }
+    }
+
+    fn send(&self, method: &str, uri: &str, token: &str, body: Option<Value>) -> Response {
+        common::send(&self.store, &self.config, bearer(request(method, uri, body), token))
+    }
+
+    /// Starts a session, returning its name and the host's token
+    fn host(&self) -> (String, String) {
+        let body = json_body(&self.send("POST", "/host", "", Some(json!({ "public": false, "host_name": "Alice" }))));
+        (body["session_name"].as_str().unwrap().into(), body["host_secret"].as_str().unwrap().into())
+    }
+
+    /// Joins a session and gets let in, returning the client's token
+    fn join(&self, session_name: &str, host_token: &str, client_name: &str) -> String {
+        let res = self.send("POST", "/join", "", Some(json!({
+            "session_name": session_name,
+            "client_name": client_name,
+            "rtc_offer": "offer",
+        })));
+        let token = json_body(&res)["client_secret"].as_str().unwrap().to_string();
+
+        let res = self.send("POST", "/host/decision", host_token, Some(json!({
+            "session_name": session_name,
+            "client_name": client_name,
+            "accept": true,
+        })));
+        assert_eq!(res.status(), 200);
+
+        token
+    }
+
+    fn host_messages(&self, session_name: &str, token: &str) -> u16 {
+        self.send("GET", &format!("/host/messages?session_name={}", encode(session_name)), token, None)
+            .status()
+            .as_u16()
+    }
+
+    fn client_messages(&self, session_name: &str, client_name: &str, token: &str) -> u16 {
+        self.send("GET", &format!(
+            "/join/messages?session_name={}&client_name={}",
+            encode(session_name),
+            encode(client_name),
+        ), token, None)
+            .status()
+            .as_u16()
+    }
+}
+
+#[test]
+fn tokens_only_vouch_for_what_they_were_issued_for() {
+    let server = Server::new();
+    let (session_name, host_token) = server.host();
+    let (other_session, _) = server.host();
+    let bob_token = server.join(&session_name, &host_token, "Bob");
+
+    assert!(host_token.starts_with("v1."));
+    assert_eq!(server.host_messages(&session_name, &host_token), 200);
+    assert_eq!(server.client_messages(&session_name, "Bob", &bob_token), 200);
+
+    // Wrong session, wrong role, wrong client
+    assert_eq!(server.host_messages(&other_session, &host_token), 401);
+    assert_eq!(server.host_messages(&session_name, &bob_token), 401);
+    assert_eq!(server.client_messages(&session_name, "Carol", &bob_token), 401);
+
+    // Or tampered with
+    let (payload, signature) = host_token.rsplit_once('.').unwrap();
+    let forged = format!("{payload}.{}", signature.chars().rev().collect::<String>());
+    assert_eq!(server.host_messages(&session_name, &forged), 401);
+
+    // Signed with some other key
+    let config = Config { token_key: Some("some other key, also thirty-two characters".into()), ..server.config.clone() };
+    let other = Server { store: MemoryStore::with_config(&config), config };
+    let (_, other_token) = other.host();
+    let (payload, _) = host_token.rsplit_once('.').unwrap();
+    let (_, other_signature) = other_token.rsplit_once('.').unwrap();
+    assert_eq!(server.host_messages(&session_name, &format!("{payload}.{other_signature}")), 401);
+}
+
+#[test]
+fn tokens_expire() {
+    let server = Server::new();
+    let (session_name, host_token) = server.host();
+
+    // Heartbeats keep the session going, but not the token they were sent with
+    for _ in 0..6 {
+        server.store.advance_clock(500);
+        assert_eq!(server.host_messages(&session_name, &host_token), 200);
+    }
+
+    server.store.advance_clock(600);
+    assert_eq!(server.host_messages(&session_name, &host_token), 401);
+}
+
+#[test]
+fn staying_active_keeps_the_tokens_coming() {
+    let server = Server::new();
+    let (session_name, mut host_token) = server.host();
+    let mut bob_token = server.join(&session_name, &host_token, "Bob");
+
+    // Well past the first tokens' expiry, swapping in the fresh ones as they come
+    let mut renewals = 0;
+    for _ in 0..10 {
+        server.store.advance_clock(500);
+
+        let res = server.send("POST", "/host/heartbeat", &host_token, Some(json!({ "session_name": session_name })));
+        assert_eq!(res.status(), 200);
+        if let Some(token) = json_body(&res)["host_secret"].as_str() {
+            host_token = token.into();
+            renewals += 1;
+        }
+
+        let res = server.send("GET", &format!(
+            "/join/messages?session_name={}&client_name=Bob&wait=0",
+            encode(&session_name),
+        ), &bob_token, None);
+        assert_eq!(res.status(), 200);
+        if let Some(token) = json_body(&res)["client_secret"].as_str() {
+            bob_token = token.into();
+        }
+    }
+    assert_eq!(server.host_messages(&session_name, &host_token), 200);
+    assert_eq!(server.client_messages(&session_name, "Bob", &bob_token), 200);
+
+    // Only once they're half way to expiring, not on every request
+    assert_eq!(renewals, 2);
+
+    // And nobody else gets the name while they're still good
+    server.store.delete_session(&session_name).unwrap();
+    server.store.advance_clock(2500);
+    assert!(server.store.create_session(&session_name, false, "Mallory", &SessionOptions::default()).unwrap().is_none());
+}
+
+#[test]
+fn revoked_tokens_stop_working() {
+    let server = Server::new();
+    let (session_name, host_token) = server.host();
+    let bob_token = server.join(&session_name, &host_token, "Bob");
+    let carol_token = server.join(&session_name, &host_token, "Carol");
+
+    // Carol leaves, and her token goes with her
+    let res = server.send("POST", "/join/leave", &carol_token, Some(json!({
+        "session_name": session_name,
+        "client_name": "Carol",
+    })));
+    assert_eq!(res.status(), 200);
+    assert_eq!(server.client_messages(&session_name, "Carol", &carol_token), 401);
+
+    // Bob gets kicked, and has a little while to read about it
+    let res = server.send("POST", "/host/kick", &host_token, Some(json!({
+        "session_name": session_name,
+        "client_name": "Bob",
+    })));
+    assert_eq!(res.status(), 200);
+    let res = server.send("GET", &format!(
+        "/join/messages?session_name={}&client_name=Bob&wait=0",
+        encode(&session_name),
+    ), &bob_token, None);
+    assert_eq!(res.status(), 200);
+    assert!(json_body(&res).get("client_secret").is_none());
+
+    server.store.advance_clock(120);
+    assert_eq!(server.client_messages(&session_name, "Bob", &bob_token), 401);
+
+    // Coming back gets him a new token, the old one stays revoked
+    let new_bob_token = server.join(&session_name, &host_token, "Bob");
+    assert_eq!(server.client_messages(&session_name, "Bob", &new_bob_token), 200);
+    assert_eq!(server.client_messages(&session_name, "Bob", &bob_token), 401);
+
+    // Closing the session revokes the host right away
+    let res = server.send("DELETE", &format!("/host?session_name={}", encode(&session_name)), &host_token, None);
+    assert_eq!(res.status(), 200);
+    assert_eq!(server.host_messages(&session_name, &host_token), 401);
+}
@@ -0,0 +1,284 @@
+//! Trickle ICE: candidates sent as they're gathered, in order with the offer and answer
+
+mod common;
+
+use common::{get_as, host_with, join, json_body, messages, post};
+use rust_signalling::{config::{Config, MailboxOverflow}, memory_store::MemoryStore};
+use serde_json::{json, Value};
+use spin_sdk::http::Response;
+use urlencoding::encode;
+
+/// The messages waiting at uri, read without acknowledging anything
+fn waiting(store: &MemoryStore, uri: &str, secret: &str) -> Vec<Value> {
+    messages(&get_as(store, &format!("{uri}&wait=0"), secret))
+}
+
+fn types(messages: &[Value]) -> Vec<&str> {
+    messages.iter().map(|message| message["type"].as_str().unwrap()).collect()
+}
+
+struct Session {
+    name: String,
+    host_secret: String,
+}
+
+impl Session {
+    fn start(store: &MemoryStore, body: Value) -> Self {
+        let (name, host_secret) = host_with(store, body);
+        Self { name, host_secret }
+    }
+
+    fn join(&self, store: &MemoryStore, client_name: &str) -> String {
+        join(store, &self.name, client_name)
+    }
+
+    fn accept(&self, store: &MemoryStore, client_name: &str) {
+        let res = post(store, "/host/decision", json!({
+            "session_name": self.name,
+            "client_name": client_name,
+            "host_secret": self.host_secret,
+            "accept": true,
+        }));
+        assert_eq!(res.status(), 200);
+    }
+
+    /// Posts to /join/candidates as the client, with whatever else is in body
+    fn client_sends(&self, store: &MemoryStore, client_name: &str, client_secret: &str, mut body: Value) -> Response {
+        body["session_name"] = json!(self.name);
+        body["client_name"] = json!(client_name);
+        body["client_secret"] = json!(client_secret);
+        post(store, "/join/candidates", body)
+    }
+
+    fn host_sends(&self, store: &MemoryStore, client_name: &str, messages: Value) -> Response {
+        post(store, "/join/response", json!({
+            "session_name": self.name,
+            "client_name": client_name,
+            "host_secret": self.host_secret,
+            "messages": messages,
+        }))
+    }
+
+    fn host_messages(&self, store: &MemoryStore) -> Vec<Value> {
+        waiting(store, &format!("/host/messages?session_name={}", encode(&self.name)), &self.host_secret)
+    }
+
+    fn client_messages(&self, store: &MemoryStore, client_name: &str, client_secret: &str) -> Vec<Value> {
+        let uri = format!("/join/messages?session_name={}&client_name={}", encode(&self.name), encode(client_name));
+        waiting(store, &uri, client_secret)
+    }
+}
+
+#[test]
+fn candidates_can_be_sent_one_at_a_time() {
+    let store = MemoryStore::new();
+    let session = Session::start(&store, json!({ "public": false, "host_name": "Alice" }));
+    let bob_secret = session.join(&store, "Bob");
+    session.accept(&store, "Bob");
+
+    for body in [
+        json!({ "candidate": "candidate:bob 1" }),
+        json!({ "candidate": "candidate:bob 2" }),
+        json!({ "end_of_candidates": true, "ufrag": "abcd", "mid": "0" }),
+    ] {
+        assert_eq!(session.client_sends(&store, "Bob", &bob_secret, body).status(), 200);
+    }
+
+    let received = session.host_messages(&store);
+    assert_eq!(received[1..], [
+        json!({ "type": "ice_candidate", "client_name": "Bob", "candidates": ["candidate:bob 1"] }),
+        json!({ "type": "ice_candidate", "client_name": "Bob", "candidates": ["candidate:bob 2"] }),
+        json!({ "type": "end_of_candidates", "client_name": "Bob", "ufrag": "abcd", "mid": "0" }),
+    ]);
+
+    // Something has to be sent though
+    assert_eq!(session.client_sends(&store, "Bob", &bob_secret, json!({})).status(), 400);
+}
+
+#[test]
+fn host_candidates_follow_the_answer() {
+    let store = MemoryStore::new();
+    let session = Session::start(&store, json!({ "public": false, "host_name": "Alice" }));
+    let bob_secret = session.join(&store, "Bob");
+    session.accept(&store, "Bob");
+
+    // Alice's first candidate beats her answer to the server
+    let candidate = |candidate: &str| json!({ "type": "ice_candidate", "candidates": [candidate] });
+    assert_eq!(session.host_sends(&store, "Bob", candidate("candidate:alice 1")).status(), 200);
+    assert_eq!(types(&session.client_messages(&store, "Bob", &bob_secret)), ["join_accepted"]);
+
+    let answer = json!({ "type": "answer", "answer": { "type": "answer", "sdp": "alice's answer" } });
+    assert_eq!(session.host_sends(&store, "Bob", json!([answer, candidate("candidate:alice 2")])).status(), 200);
+
+    // After that they go straight through
+    assert_eq!(session.host_sends(&store, "Bob", candidate("candidate:alice 3")).status(), 200);
+
+    assert_eq!(session.client_messages(&store, "Bob", &bob_secret)[1..], [
+        answer,
+        candidate("candidate:alice 1"),
+        candidate("candidate:alice 2"),
+        candidate("candidate:alice 3"),
+    ]);
+}
+
+#[test]
+fn waiting_clients_candidates_follow_their_offer() {
+    let store = MemoryStore::new();
+    let session = Session::start(&store, json!({
+        "public": false,
+        "host_name": "Alice",
+        "max_clients": 1,
+        "waiting_list": true,
+    }));
+    let bob_secret = session.join(&store, "Bob");
+    let carol_secret = session.join(&store, "Carol");
+
+    // Carol gets going while she's in line
+    let res = session.client_sends(&store, "Carol", &carol_secret, json!({ "candidates": ["candidate:carol"] }));
+    assert_eq!(res.status(), 200);
+
+    let res = post(&store, "/join/leave", json!({
+        "session_name": session.name,
+        "client_name": "Bob",
+        "client_secret": bob_secret,
+    }));
+    assert_eq!(res.status(), 200);
+    assert_eq!(types(&session.host_messages(&store)), ["start_join", "client_left", "start_join"]);
+
+    session.accept(&store, "Carol");
+    let received = session.host_messages(&store);
+    assert_eq!(types(&received), ["start_join", "client_left", "start_join", "ice_candidate"]);
+    assert_eq!(received[3]["candidates"], json!(["candidate:carol"]));
+}
+
+#[test]
+fn held_candidates_go_with_the_client() {
+    let store = MemoryStore::new();
+    let session = Session::start(&store, json!({ "public": false, "host_name": "Alice" }));
+    let bob_secret = session.join(&store, "Bob");
+
+    let res = session.client_sends(&store, "Bob", &bob_secret, json!({ "candidate": "candidate:old" }));
+    assert_eq!(res.status(), 200);
+    let res = post(&store, "/join/leave", json!({
+        "session_name": session.name,
+        "client_name": "Bob",
+        "client_secret": bob_secret,
+    }));
+    assert_eq!(res.status(), 200);
+
+    // A new Bob doesn't inherit them
+    session.join(&store, "Bob");
+    session.accept(&store, "Bob");
+    assert_eq!(types(&session.host_messages(&store)), ["start_join", "client_left", "start_join"]);
+}
+
+#[test]
+fn hosts_have_their_own_candidates_endpoint() {
+    let store = MemoryStore::new();
+    let session = Session::start(&store, json!({ "public": false, "host_name": "Alice" }));
+    let bob_secret = session.join(&store, "Bob");
+    session.accept(&store, "Bob");
+
+    let host_sends = |secret: &str, client_name: &str, mut body: Value| {
+        body["session_name"] = json!(session.name);
+        body["client_name"] = json!(client_name);
+        body["host_secret"] = json!(secret);
+        post(&store, "/host/candidates", body)
+    };
+
+    assert_eq!(host_sends(&session.host_secret, "Bob", json!({ "candidates": ["candidate:alice 1"] })).status(), 200);
+    assert_eq!(host_sends(&session.host_secret, "Bob", json!({ "candidate": "candidate:alice 2", "end_of_candidates": true })).status(), 200);
+
+    // Only the host, only to someone in the session, and only real candidates
+    assert_eq!(host_sends(&bob_secret, "Bob", json!({ "candidate": "candidate:forged" })).status(), 401);
+    assert_eq!(host_sends(&session.host_secret, "Carol", json!({ "candidate": "candidate:alice" })).status(), 404);
+    assert_eq!(host_sends(&session.host_secret, "Bob", json!({ "candidates": [""] })).status(), 400);
+    assert_eq!(host_sends(&session.host_secret, "Bob", json!({ "candidates": [1] })).status(), 400);
+
+    let answer = json!({ "type": "answer", "answer": { "type": "answer", "sdp": "alice's answer" } });
+    assert_eq!(session.host_sends(&store, "Bob", answer).status(), 200);
+
+    // The same messages clients send, minus who they're from
+    assert_eq!(session.client_messages(&store, "Bob", &bob_secret)[2..], [
+        json!({ "type": "ice_candidate", "candidates": ["candidate:alice 1"] }),
+        json!({ "type": "ice_candidate", "candidates": ["candidate:alice 2"] }),
+        json!({ "type": "end_of_candidates" }),
+    ]);
+}
+
+#[test]
+fn a_full_hold_doesnt_stop_the_answer() {
+    let config = Config { max_mailbox_length: 4, mailbox_overflow: MailboxOverflow::DropOldest, ..Config::default() };
+    let store = MemoryStore::with_config(&config);
+    let session = Session::start(&store, json!({ "public": false, "host_name": "Alice" }));
+    let bob_secret = session.join(&store, "Bob");
+    session.accept(&store, "Bob");
+
+    let candidate = |candidate: &str| json!({ "type": "ice_candidate", "candidates": [candidate] });
+    for i in 1..=4 {
+        assert_eq!(session.host_sends(&store, "Bob", candidate(&format!("candidate:alice {i}"))).status(), 200);
+    }
+
+    // Only three of them fit behind the answer, the oldest goes, and so does join_accepted
+    let answer = json!({ "type": "answer", "answer": { "type": "answer", "sdp": "alice's answer" } });
+    let res = session.host_sends(&store, "Bob", answer.clone());
+    assert_eq!(res.status(), 200);
+    assert_eq!(json_body(&res)["dropped"], 2);
+
+    assert_eq!(session.client_messages(&store, "Bob", &bob_secret), [
+        answer,
+        candidate("candidate:alice 2"),
+        candidate("candidate:alice 3"),
+        candidate("candidate:alice 4"),
+    ]);
+}
+
+#[test]
+fn held_candidates_last_as_long_as_the_session() {
+    let store = MemoryStore::new();
+    let session = Session::start(&store, json!({ "public": false, "host_name": "Alice" }));
+    let bob_secret = session.join(&store, "Bob");
+
+    let res = session.client_sends(&store, "Bob", &bob_secret, json!({ "candidate": "candidate:bob" }));
+    assert_eq!(res.status(), 200);
+
+    // Alice takes her time deciding, but keeps the session going meanwhile
+    for _ in 0..4 {
+        store.advance_clock(300);
+        let res = post(&store, "/host/heartbeat", json!({
+            "session_name": session.name,
+            "host_secret": session.host_secret,
+        }));
+        assert_eq!(res.status(), 200);
+    }
+
+    session.accept(&store, "Bob");
+    assert_eq!(types(&session.host_messages(&store)), ["start_join", "ice_candidate"]);
+}
+
+#[test]
+fn candidates_have_to_look_like_candidates() {
+    let store = MemoryStore::new();
+    let session = Session::start(&store, json!({ "public": false, "host_name": "Alice" }));
+    let bob_secret = session.join(&store, "Bob");
+    session.accept(&store, "Bob");
+
+    // Either the candidate line itself, or the RTCIceCandidateInit it came in
+    let line = "candidate:1 1 udp 2122260223 10.0.0.2 50000 typ host";
+    let init = json!({ "candidate": line, "sdpMid": "0", "sdpMLineIndex": 0 }).to_string();
+    for candidate in [line, init.as_str()] {
+        let res = session.client_sends(&store, "Bob", &bob_secret, json!({ "candidate": candidate }));
+        assert_eq!(res.status(), 200);
+    }
+
+    for candidate in ["not a candidate", "candidate:", "{\"candidate\": \"not a candidate\"}", "{\"sdpMid\": \"0\"}"] {
+        let res = session.client_sends(&store, "Bob", &bob_secret, json!({ "candidate": candidate }));
+        assert_eq!(res.status(), 400);
+    }
+
+    // Hosts' candidates too
+    let malformed = json!({ "type": "ice_candidate", "candidates": ["not a candidate"] });
+    assert_eq!(session.host_sends(&store, "Bob", malformed).status(), 400);
+
+    assert_eq!(types(&session.host_messages(&store)), ["start_join", "ice_candidate", "ice_candidate"]);
+}
@@ -1,4 +1,4 @@
 #!/usr/bin/env bash
 # Build and up
 spin build
-spin up
\ No newline at end of file
+SPIN_CONFIG_REDIS_ADDRESS="$(cat redis.env 2>/dev/null)" spin up
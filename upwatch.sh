@@ -1,4 +1,4 @@
 #!/usr/bin/env bash
 # Automatically rebuilds and re-ups when files change
 # Requires nodemon
-nodemon -e rs,txt,toml,html -x "spin build && RUST_LOG=warn spin up"
\ No newline at end of file
+nodemon -e rs,txt,toml,html -x "spin build && SPIN_CONFIG_REDIS_ADDRESS=\"$(cat redis.env 2>/dev/null)\" RUST_LOG=warn spin up"
\ No newline at end of file